@@ -0,0 +1,170 @@
+//! Throughput of concurrent backups on the server, and responsiveness of its executor meanwhile.
+//!
+//! Each client ciphers its stream, which is then deciphered and compressed as the server does.
+//! Both pipelines are measured: inline, where the tasks of a backup encrypt, decrypt and compress
+//! the data themselves, and offloaded, where this work runs on blocking threads.
+//! A probe task measures how late the executor wakes it up, as it would the accept loop.
+//! The time taken to encrypt a single frame is how long a worker thread is held
+//! by each frame of an inline pipeline, whatever the number of cores.
+//!
+//! Usage: `cargo bench --bench pipeline -- [clients] [MiB per client] [KiB per block]`
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use forgedbackup::{fce, fdgse, DEFAULT_BLOCK_SIZE, DUPLEX_BUFFER_SIZE};
+use tokio::io::{duplex, AsyncWriteExt};
+
+const MIB: usize = 1 << 20;
+
+// Text compresses about as well as typical backups
+fn sample_data() -> Vec<u8> {
+    let mut data = Vec::with_capacity(MIB);
+    let mut i = 0u64;
+    while data.len() < MIB {
+        data.extend_from_slice(format!("{i} {}\n", i.wrapping_mul(0x9E37_79B9)).as_bytes());
+        i += 1;
+    }
+    data.truncate(MIB);
+    data
+}
+
+async fn backup(
+    data: Arc<Vec<u8>>,
+    size: usize,
+    block_size: u32,
+    key: fdgse::CipherKey,
+    offload: bool,
+) {
+    let (mut source_tx, mut source_rx) = duplex(DUPLEX_BUFFER_SIZE);
+    let (mut network_tx, mut network_rx) = duplex(DUPLEX_BUFFER_SIZE);
+    let (mut plain_tx, mut plain_rx) = duplex(DUPLEX_BUFFER_SIZE);
+    let mut session = fdgse::Session::new(block_size, key);
+    session.offload = offload;
+    let workers = if offload { fce::default_workers() } else { 0 };
+
+    let source = tokio::spawn(async move {
+        for _ in 0..size / data.len() {
+            source_tx.write_all(&data).await.unwrap();
+        }
+    });
+    let client = tokio::spawn(async move {
+        fdgse::cipher_stream(&mut source_rx, &mut network_tx, &session)
+            .await
+            .unwrap();
+    });
+    let server = tokio::spawn(async move {
+        fdgse::decipher_stream(&mut network_rx, &mut plain_tx, &session)
+            .await
+            .unwrap();
+    });
+    let compressor = tokio::spawn(async move {
+        let header = fce::ArchiveHeader::new("bench", 0, fce::Compression::default(), block_size);
+        fce::compress_stream_with_blocks(
+            &mut plain_rx,
+            &mut tokio::io::sink(),
+            &header,
+            None,
+            workers,
+        )
+        .await
+        .unwrap();
+    });
+
+    for handle in [source, client, server, compressor] {
+        handle.await.unwrap();
+    }
+}
+
+// Runs the backups of all clients at once, printing their throughput and how late the executor was meanwhile
+async fn run(data: &Arc<Vec<u8>>, clients: usize, size: usize, block_size: u32, offload: bool) {
+    let key = fdgse::generate_key();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let probe = {
+        let done = done.clone();
+        tokio::spawn(async move {
+            let mut delays = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                delays.push(start.elapsed().saturating_sub(Duration::from_millis(1)));
+            }
+            delays.sort();
+            delays
+        })
+    };
+
+    let start = Instant::now();
+    let backups: Vec<_> = (0..clients)
+        .map(|_| tokio::spawn(backup(data.clone(), size, block_size, key, offload)))
+        .collect();
+    for backup in backups {
+        backup.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    done.store(true, Ordering::Relaxed);
+    let delays = probe.await.unwrap();
+    let percentile = |p: usize| delays[(delays.len() - 1) * p / 100];
+
+    let total = (clients * size / MIB) as f64;
+    println!(
+        "{}: {total} MiB in {:.2?}, {:.1} MiB/s, executor late by {:.2?} (median), {:.2?} (p99), {:.2?} (max)",
+        if offload { "offloaded" } else { "inline" },
+        elapsed,
+        total / elapsed.as_secs_f64(),
+        percentile(50),
+        percentile(99),
+        percentile(100),
+    );
+}
+
+// Shortest time taken to encrypt a single frame
+fn frame_encryption_time(data: &[u8], block_size: usize) -> Duration {
+    let cipher = Aes256Gcm::new(&fdgse::generate_key());
+    let frame = data.repeat(block_size.div_ceil(data.len()));
+    (0..8)
+        .map(|_| {
+            let start = Instant::now();
+            cipher
+                .encrypt(&Nonce::default(), &frame[..block_size])
+                .unwrap();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+#[tokio::main]
+async fn main() {
+    // `cargo bench` adds flags of its own
+    let args: Vec<usize> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .map(|arg| arg.parse().expect("Invalid argument"))
+        .collect();
+    let clients = args.first().copied().unwrap_or(8);
+    let size = args.get(1).copied().unwrap_or(64) * MIB;
+    let block_size = args.get(2).map_or(DEFAULT_BLOCK_SIZE, |kib| {
+        u32::try_from(kib << 10).expect("Invalid block size")
+    });
+
+    let data = Arc::new(sample_data());
+    println!(
+        "{clients} clients x {} MiB, {} KiB blocks, {} cores",
+        size / MIB,
+        block_size >> 10,
+        fce::default_workers(),
+    );
+    for offload in [false, true] {
+        run(&data, clients, size, block_size, offload).await;
+    }
+    println!(
+        "encrypting one frame takes {:.2?}",
+        frame_encryption_time(&data, block_size as usize),
+    );
+}
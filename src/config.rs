@@ -1,7 +1,12 @@
 use core::net::SocketAddr;
-use std::{collections::HashMap, path::PathBuf};
-use toml::Table;
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+use toml::{Table, Value};
 
+use crate::fadc::{CrawlMode, CrawlOptions, Source};
+use crate::fce::Compression;
 use crate::fdgse::CipherKey;
 use crate::fsas::KeyPair;
 
@@ -12,7 +17,8 @@ pub struct ServerInfo {
     pub hostname: Hostname,
     pub addr: SocketAddr,
     pub keypair: KeyPair,
-    pub cipher_key: CipherKey,
+    /// Key mixed into the keys of the sessions, if any.
+    pub psk: Option<CipherKey>,
 }
 
 #[allow(clippy::module_name_repetitions)]
@@ -20,21 +26,177 @@ pub struct ServerInfo {
 pub struct ClientConfig {
     pub servers: Vec<ServerInfo>,
     pub hostname: Hostname,
-    pub backed_up_dir: PathBuf,
+    pub backed_up_dirs: Vec<Source>,
+    pub crawl_options: CrawlOptions,
+    /// Amount of data sent with a key before switching to the next one, in bytes.
+    pub rekey_after: u64,
+}
+
+/// How the backups of a client are stored on the server.
+#[derive(Clone)]
+pub struct StorageConfig {
+    pub compression: Compression,
+    /// Extensions of files stored without compression.
+    pub incompressible_extensions: Vec<String>,
+    /// Number of blocks of a backup compressed in parallel.
+    pub compression_workers: usize,
+    /// Preferred size of the blocks sent by the client and compressed, in bytes.
+    pub block_size: u32,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            compression: Compression::default(),
+            incompressible_extensions: crate::fadc::DEFAULT_INCOMPRESSIBLE_EXTENSIONS
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            compression_workers: crate::fce::default_workers(),
+            block_size: crate::DEFAULT_BLOCK_SIZE,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct ClientInfo {
     pub keypair: KeyPair,
-    pub cipher_key: CipherKey,
+    /// Key mixed into the keys of the sessions, if any.
+    pub psk: Option<CipherKey>,
+    pub storage: StorageConfig,
 }
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone)]
 pub struct ServerConfig {
+    /// Name of the server, as known by its clients.
+    pub hostname: Hostname,
     pub listening_socker_addr: SocketAddr,
     pub client_infos: HashMap<Hostname, ClientInfo>,
     pub backup_dir: PathBuf,
+    /// Storage of clients without specific settings.
+    pub storage: StorageConfig,
+}
+
+// Either a single directory, or a table of named directories
+fn read_sources(value: &Value) -> Vec<Source> {
+    let parse_path = |path: &Value| {
+        path.as_str()
+            .expect("Could not parse backed_up_dir in configuration file")
+            .parse::<PathBuf>()
+            .expect("Could not parse backed_up_dir in configuration file")
+    };
+
+    match value {
+        Value::String(_) => vec![Source {
+            name: String::new(),
+            path: parse_path(value),
+        }],
+        Value::Table(sources) => sources
+            .iter()
+            .map(|(name, path)| {
+                let mut components = Path::new(name).components();
+                assert!(
+                    matches!(components.next(), Some(Component::Normal(_)))
+                        && components.next().is_none(),
+                    "Invalid source name {name} in configuration file"
+                );
+                Source {
+                    name: name.clone(),
+                    path: parse_path(path),
+                }
+            })
+            .collect(),
+        _ => panic!("Could not parse backed_up_dir in configuration file"),
+    }
+}
+
+fn read_patterns(config: &Table, key: &str) -> Vec<String> {
+    read_strings(config, key).unwrap_or_default()
+}
+
+fn read_strings(config: &Table, key: &str) -> Option<Vec<String>> {
+    config.get(key).map(|values| {
+        values
+            .as_array()
+            .unwrap_or_else(|| panic!("Could not parse {key} in configuration file"))
+            .iter()
+            .map(|value| {
+                value
+                    .as_str()
+                    .unwrap_or_else(|| panic!("Could not parse {key} in configuration file"))
+                    .to_string()
+            })
+            .collect()
+    })
+}
+
+// Pre-shared keys are optional, and so is the directory holding them
+fn read_cipher_keys_dir(config: &Table) -> Option<PathBuf> {
+    config.get("cipher_keys_dir").map(|dir| {
+        dir.as_str()
+            .expect("Could not parse cipher_keys_dir in configuration file")
+            .parse::<PathBuf>()
+            .expect("Could not parse cipher_keys_dir in configuration file")
+    })
+}
+
+fn read_psk(cipher_keys_dir: Option<&Path>, name: &str) -> Option<CipherKey> {
+    cipher_keys_dir.and_then(|dir| {
+        crate::fdgse::read_optional_key(dir.join(format!("{name}.aes")).to_str().unwrap())
+    })
+}
+
+impl StorageConfig {
+    // Reads the settings found in `config`, the others being those of `defaults`
+    fn read(config: &Table, defaults: &Self) -> Self {
+        let compression = config.get("compression").map(|compression| {
+            Compression::try_from(
+                compression
+                    .as_str()
+                    .expect("Could not parse compression in configuration file"),
+            )
+            .expect("Could not parse compression in configuration file")
+        });
+
+        let compression_workers = config.get("compression_workers").map(|workers| {
+            workers
+                .as_integer()
+                .and_then(|workers| usize::try_from(workers).ok())
+                .filter(|&workers| workers > 0)
+                .expect("Could not parse compression_workers in configuration file")
+        });
+
+        let block_size = config.get("block_size").map(|block_size| {
+            block_size
+                .as_integer()
+                .and_then(|block_size| u32::try_from(block_size).ok())
+                .filter(|block_size| {
+                    (crate::MIN_BLOCK_SIZE..=crate::MAX_BLOCK_SIZE).contains(block_size)
+                })
+                .expect("Could not parse block_size in configuration file, it must be between 32 KiB and 4 MiB")
+        });
+
+        Self {
+            compression: compression.unwrap_or(defaults.compression),
+            incompressible_extensions: read_strings(config, "incompressible_extensions")
+                .unwrap_or_else(|| defaults.incompressible_extensions.clone()),
+            compression_workers: compression_workers.unwrap_or(defaults.compression_workers),
+            block_size: block_size.unwrap_or(defaults.block_size),
+        }
+    }
+}
+
+// Settings specific to a client, in a [clients.<hostname>] table
+fn client_table<'a>(config: &'a Table, hostname: &str) -> Option<&'a Table> {
+    config
+        .get("clients")
+        .and_then(|clients| clients.get(hostname))
+        .map(|client| {
+            client.as_table().unwrap_or_else(|| {
+                panic!("Could not parse clients.{hostname} in configuration file")
+            })
+        })
 }
 
 impl ClientConfig {
@@ -60,17 +222,23 @@ impl ClientConfig {
             .parse::<PathBuf>()
             .expect("Could not parse verifying_keys_dir in configuration file");
 
-        let cipher_keys_dir = config["cipher_keys_dir"]
-            .as_str()
-            .expect("Missing cipher_keys_dir in configuration file")
-            .parse::<PathBuf>()
-            .expect("Could not parse cipher_keys_dir in configuration file");
+        let cipher_keys_dir = read_cipher_keys_dir(&config);
 
-        let backed_up_dir = config["backed_up_dir"]
-            .as_str()
-            .expect("Missing backed_up_dir in configuration file")
-            .parse::<PathBuf>()
-            .expect("Could not parse backed_up_dir in configuration file");
+        let backed_up_dirs = read_sources(&config["backed_up_dir"]);
+
+        let crawl_options = CrawlOptions {
+            include: read_patterns(&config, "include"),
+            exclude: read_patterns(&config, "exclude"),
+            mode: config
+                .get("crawl_mode")
+                .map_or_else(CrawlMode::default, |mode| {
+                    CrawlMode::try_from(
+                        mode.as_str()
+                            .expect("Could not parse crawl_mode in configuration file"),
+                    )
+                    .expect("Could not parse crawl_mode in configuration file")
+                }),
+        };
 
         let servers = config["servers"]
             .as_table()
@@ -97,11 +265,7 @@ impl ClientConfig {
                             crate::fsas::read_verifying_key(&verifying_key_path).unwrap()
                         },
                     },
-                    cipher_key: {
-                        let cipher_key_path =
-                            format!("{}/{}.aes", cipher_keys_dir.to_str().unwrap(), name);
-                        crate::fdgse::read_key(&cipher_key_path)
-                    },
+                    psk: read_psk(cipher_keys_dir.as_deref(), name),
                 }
             })
             .collect();
@@ -111,10 +275,23 @@ impl ClientConfig {
             .expect("Missing hostname in configuration file")
             .to_string();
 
+        let rekey_after =
+            config
+                .get("rekey_after")
+                .map_or(crate::fdgse::DEFAULT_REKEY_AFTER, |rekey_after| {
+                    rekey_after
+                        .as_integer()
+                        .and_then(|rekey_after| u64::try_from(rekey_after).ok())
+                        .filter(|&rekey_after| rekey_after > 0)
+                        .expect("Could not parse rekey_after in configuration file")
+                });
+
         Self {
             servers,
             hostname,
-            backed_up_dir,
+            backed_up_dirs,
+            crawl_options,
+            rekey_after,
         }
     }
 }
@@ -136,6 +313,11 @@ impl ServerConfig {
             .parse()
             .expect("Could not parse listening_socker_addr in configuration file");
 
+        let hostname = config["hostname"]
+            .as_str()
+            .expect("Missing hostname in configuration file")
+            .to_string();
+
         let signing_keys_dir = config["signing_keys_dir"]
             .as_str()
             .expect("Missing signing_keys_dir in configuration file")
@@ -148,11 +330,7 @@ impl ServerConfig {
             .parse::<PathBuf>()
             .expect("Could not parse verifying_keys_dir in configuration file");
 
-        let cipher_keys_dir = config["cipher_keys_dir"]
-            .as_str()
-            .expect("Missing cipher_keys_dir in configuration file")
-            .parse::<PathBuf>()
-            .expect("Could not parse cipher_keys_dir in configuration file");
+        let cipher_keys_dir = read_cipher_keys_dir(&config);
 
         let backup_dir = config["backup_dir"]
             .as_str()
@@ -160,6 +338,8 @@ impl ServerConfig {
             .parse::<PathBuf>()
             .expect("Could not parse backup_dir in configuration file");
 
+        let default_storage = StorageConfig::read(&config, &StorageConfig::default());
+
         let mut client_infos = HashMap::new();
 
         for entry in
@@ -188,16 +368,11 @@ impl ServerConfig {
                 crate::fsas::read_verifying_key(&verifying_key_path)
                     .expect("Could not read verifying key")
             };
-            let cipher_key = {
-                let cipher_key_path = format!(
-                    "{}/{}.aes",
-                    cipher_keys_dir
-                        .to_str()
-                        .expect("Could not convert verifying keys directory to string"),
-                    hostname
-                );
-                crate::fdgse::read_key(&cipher_key_path)
-            };
+            let psk = read_psk(cipher_keys_dir.as_deref(), &hostname);
+            let storage = client_table(&config, &hostname).map_or_else(
+                || default_storage.clone(),
+                |client| StorageConfig::read(client, &default_storage),
+            );
             client_infos.insert(
                 hostname,
                 ClientInfo {
@@ -205,15 +380,18 @@ impl ServerConfig {
                         signing_key,
                         verifying_key,
                     },
-                    cipher_key,
+                    psk,
+                    storage,
                 },
             );
         }
 
         Self {
+            hostname,
             listening_socker_addr: listening_socket_addr,
             client_infos,
             backup_dir,
+            storage: default_storage,
         }
     }
 }
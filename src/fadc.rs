@@ -1,103 +1,1753 @@
 //! Forged Asynchronous Directory Crawler (fADC)
 
-use std::io::ErrorKind::UnexpectedEof;
-use std::path::PathBuf;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::io::ErrorKind::{BrokenPipe, InvalidData, InvalidInput, UnexpectedEof};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime};
 use tokio::fs::ReadDir;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
 
+use crate::fce::Chunk;
 use crate::BUFFER_SIZE;
 
-async fn crawl_dir(mut directory: ReadDir, tx: &mut DuplexStream) -> Result<(), std::io::Error> {
-    Box::pin(async move {
-        while let Some(entry) = directory.next_entry().await? {
-            let metadata = entry.metadata().await?;
+/// A point in time, as seconds and nanoseconds relative to the Unix epoch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
 
-            if metadata.is_file() || metadata.is_symlink() {
-                let path = entry.path();
-                let path_bytes = path.as_os_str().as_encoded_bytes();
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(d) => Self {
+                secs: i64::try_from(d.as_secs()).unwrap_or(i64::MAX),
+                nanos: d.subsec_nanos(),
+            },
+            Err(e) => {
+                let d = e.duration();
+                Self {
+                    secs: -i64::try_from(d.as_secs()).unwrap_or(i64::MAX),
+                    nanos: d.subsec_nanos(),
+                }
+            }
+        }
+    }
+}
 
-                tx.write_u64_le(path_bytes.len() as u64).await?;
-                tx.write_all(path_bytes).await?;
+// Formats as UTC date and time, e.g. `2024-09-01 13:37:00`
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Converts days since the Unix epoch to a date of the proleptic Gregorian calendar
+        let days = self.secs.div_euclid(86400) + 719_468;
+        let secs = self.secs.rem_euclid(86400);
 
-                let file_size = metadata.len();
-                tx.write_u64_le(file_size).await?;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
 
-                let file = tokio::fs::File::open(&path).await?;
-                let mut src = tokio::io::BufReader::new(file);
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
 
-                log::trace!("Sending file: {:?}", path);
+impl From<Timestamp> for SystemTime {
+    fn from(time: Timestamp) -> Self {
+        let nanos = Duration::from_nanos(u64::from(time.nanos));
+        if time.secs >= 0 {
+            Self::UNIX_EPOCH + Duration::from_secs(time.secs.unsigned_abs()) + nanos
+        } else {
+            Self::UNIX_EPOCH - Duration::from_secs(time.secs.unsigned_abs()) + nanos
+        }
+    }
+}
 
-                let mut buf = vec![0; BUFFER_SIZE];
-                loop {
-                    let bytes_read = src.read(&mut buf).await?;
-                    if bytes_read == 0 {
-                        break;
-                    }
-                    tx.write_all(&buf[..bytes_read]).await?;
+/// Metadata header sent in front of every entry of the stream.
+///
+/// `ctime` cannot be set back on restore, it is only kept for reference.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+    pub ctime: Timestamp,
+}
+
+#[cfg(unix)]
+impl From<&std::fs::Metadata> for EntryMetadata {
+    fn from(metadata: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        let timestamp = |secs, nanos| Timestamp {
+            secs,
+            nanos: u32::try_from(nanos).unwrap_or(0),
+        };
+
+        Self {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            atime: timestamp(metadata.atime(), metadata.atime_nsec()),
+            mtime: timestamp(metadata.mtime(), metadata.mtime_nsec()),
+            ctime: timestamp(metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl From<&std::fs::Metadata> for EntryMetadata {
+    fn from(metadata: &std::fs::Metadata) -> Self {
+        let timestamp =
+            |time: std::io::Result<SystemTime>| time.map(Timestamp::from).unwrap_or_default();
+
+        Self {
+            mode: if metadata.permissions().readonly() {
+                0o444
+            } else {
+                0o644
+            },
+            uid: 0,
+            gid: 0,
+            atime: timestamp(metadata.accessed()),
+            mtime: timestamp(metadata.modified()),
+            ctime: timestamp(metadata.created()),
+        }
+    }
+}
+
+impl EntryMetadata {
+    async fn write_timestamp<W>(writer: &mut W, time: Timestamp) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer.write_i64_le(time.secs).await?;
+        writer.write_u32_le(time.nanos).await
+    }
+
+    async fn read_timestamp<R>(reader: &mut R) -> std::io::Result<Timestamp>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        Ok(Timestamp {
+            secs: reader.read_i64_le().await?,
+            nanos: reader.read_u32_le().await?,
+        })
+    }
+
+    pub async fn write_to<W>(&self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer.write_u32_le(self.mode).await?;
+        writer.write_u32_le(self.uid).await?;
+        writer.write_u32_le(self.gid).await?;
+        Self::write_timestamp(writer, self.atime).await?;
+        Self::write_timestamp(writer, self.mtime).await?;
+        Self::write_timestamp(writer, self.ctime).await
+    }
+
+    pub async fn read_from<R>(reader: &mut R) -> std::io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        Ok(Self {
+            mode: reader.read_u32_le().await?,
+            uid: reader.read_u32_le().await?,
+            gid: reader.read_u32_le().await?,
+            atime: Self::read_timestamp(reader).await?,
+            mtime: Self::read_timestamp(reader).await?,
+            ctime: Self::read_timestamp(reader).await?,
+        })
+    }
+
+    // Ownership is restored before permissions, as changing the owner clears setuid/setgid bits.
+    // Times are set last so that nothing else touches them afterwards.
+    fn apply(&self, path: &Path, kind: EntryKind, preserve_ownership: bool) -> std::io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            use std::os::unix::fs::{chown, fchown, lchown, OpenOptionsExt, PermissionsExt};
+            use std::os::unix::io::AsRawFd;
+
+            // Symlinks permissions cannot be changed, and they must not be followed
+            let is_symlink = kind == EntryKind::Symlink;
+            // Directories are changed through a descriptor opened without following links, so that
+            // a symlink put in their place cannot redirect the changes outside the output directory
+            let directory = if kind == EntryKind::Directory {
+                Some(
+                    std::fs::File::options()
+                        .read(true)
+                        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+                        .open(path)?,
+                )
+            } else {
+                None
+            };
+
+            if preserve_ownership {
+                if let Some(directory) = &directory {
+                    fchown(directory, Some(self.uid), Some(self.gid))?;
+                } else if is_symlink {
+                    lchown(path, Some(self.uid), Some(self.gid))?;
+                } else {
+                    chown(path, Some(self.uid), Some(self.gid))?;
+                }
+            }
+
+            if !is_symlink {
+                let permissions = std::fs::Permissions::from_mode(self.mode & 0o7777);
+                if let Some(directory) = &directory {
+                    directory.set_permissions(permissions)?;
+                } else {
+                    std::fs::set_permissions(path, permissions)?;
                 }
-            } else if metadata.is_dir() {
-                let new_directory = tokio::fs::read_dir(entry.path()).await?;
-                crawl_dir(new_directory, tx).await?;
+            }
+
+            let timespec = |time: Timestamp| libc::timespec {
+                tv_sec: time.secs,
+                tv_nsec: time.nanos.into(),
+            };
+            let times = [timespec(self.atime), timespec(self.mtime)];
+
+            let result = if let Some(directory) = &directory {
+                // SAFETY: the descriptor is open and `times` holds exactly two timespecs
+                unsafe { libc::futimens(directory.as_raw_fd(), times.as_ptr()) }
             } else {
-                log::warn!("Skipping non-file/directory: {:?}", entry.path());
+                let flags = if is_symlink {
+                    libc::AT_SYMLINK_NOFOLLOW
+                } else {
+                    0
+                };
+                let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+                // SAFETY: `path` is a valid C string and `times` holds exactly two timespecs
+                unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), flags) }
+            };
+            if result != 0 {
+                return Err(std::io::Error::last_os_error());
             }
         }
 
+        #[cfg(not(unix))]
+        {
+            let _ = preserve_ownership;
+            if kind == EntryKind::File {
+                let file = std::fs::File::options().write(true).open(path)?;
+                let times = std::fs::FileTimes::new()
+                    .set_accessed(self.atime.into())
+                    .set_modified(self.mtime.into());
+                file.set_times(times)?;
+            }
+            let mut permissions = std::fs::symlink_metadata(path)?.permissions();
+            permissions.set_readonly(self.mode & 0o222 == 0);
+            std::fs::set_permissions(path, permissions)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Tag following the last entry of the stream, before the `CrawlSummary`.
+pub const TRAILER_TAG: u8 = 0xFF;
+
+/// Type of an entry of the stream.
+///
+/// Every entry is made of its kind, its path and its metadata, followed by a payload:
+/// - `File`: size, content and whether it changed while being read
+/// - `Symlink`: target of the link
+/// - `Hardlink`: path of the previously sent entry it links to
+/// - `CharDevice` and `BlockDevice`: device number
+/// - `Directory` and `Fifo`: nothing
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File = 0,
+    Directory = 1,
+    Symlink = 2,
+    Hardlink = 3,
+    Fifo = 4,
+    CharDevice = 5,
+    BlockDevice = 6,
+}
+
+impl TryFrom<u8> for EntryKind {
+    type Error = std::io::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::File),
+            1 => Ok(Self::Directory),
+            2 => Ok(Self::Symlink),
+            3 => Ok(Self::Hardlink),
+            4 => Ok(Self::Fifo),
+            5 => Ok(Self::CharDevice),
+            6 => Ok(Self::BlockDevice),
+            _ => Err(std::io::Error::new(InvalidData, "Unknown entry kind")),
+        }
+    }
+}
+
+impl EntryKind {
+    /// Character representing the kind in listings, similar to `ls -l`.
+    #[must_use]
+    pub const fn symbol(self) -> char {
+        match self {
+            Self::File => '-',
+            Self::Directory => 'd',
+            Self::Symlink => 'l',
+            Self::Hardlink => 'h',
+            Self::Fifo => 'p',
+            Self::CharDevice => 'c',
+            Self::BlockDevice => 'b',
+        }
+    }
+
+    // Hardlinks are detected separately, as they look like regular files
+    fn of(file_type: std::fs::FileType) -> Option<Self> {
+        if file_type.is_file() {
+            return Some(Self::File);
+        } else if file_type.is_dir() {
+            return Some(Self::Directory);
+        } else if file_type.is_symlink() {
+            return Some(Self::Symlink);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+
+            if file_type.is_fifo() {
+                return Some(Self::Fifo);
+            } else if file_type.is_char_device() {
+                return Some(Self::CharDevice);
+            } else if file_type.is_block_device() {
+                return Some(Self::BlockDevice);
+            }
+        }
+
+        None
+    }
+}
+
+// Same as Linux `PATH_MAX`
+pub const DEFAULT_MAX_PATH_LEN: usize = 4096;
+
+/// Selects entries by their path in the stream.
+/// A pattern selects the paths it matches, as well as everything below them.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    // `None` selects every path
+    globs: Option<GlobSet>,
+}
+
+impl PathFilter {
+    /// Whether the filter was built without patterns.
+    #[must_use]
+    pub const fn selects_everything(&self) -> bool {
+        self.globs.is_none()
+    }
+
+    // ## Errors
+    // This function returns an error if a pattern is not a valid glob.
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> std::io::Result<Self> {
+        if patterns.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref().trim_end_matches('/');
+            let glob = Glob::new(pattern).map_err(|e| std::io::Error::new(InvalidInput, e))?;
+            builder.add(glob);
+        }
+        let globs = builder
+            .build()
+            .map_err(|e| std::io::Error::new(InvalidInput, e))?;
+
+        Ok(Self { globs: Some(globs) })
+    }
+
+    #[must_use]
+    pub fn matches(&self, path: &Path) -> bool {
+        self.globs.as_ref().is_none_or(|globs| {
+            path.ancestors()
+                .take_while(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| globs.is_match(ancestor))
+        })
+    }
+}
+
+/// Options controlling how entries are written back to disk.
+#[derive(Clone, Debug)]
+pub struct RestoreOptions {
+    /// Restore the original owner and group of entries.
+    /// This usually requires running as root.
+    pub preserve_ownership: bool,
+    /// Maximum length of paths and link targets read from the stream, in bytes.
+    pub max_path_len: usize,
+    /// Only restore the entries of this source, directly in the output directory.
+    pub source: Option<String>,
+    /// Only restore the entries matching this filter, along with everything below them.
+    pub paths: PathFilter,
+}
+
+impl RestoreOptions {
+    /// Returns whether an entry of the stream is restored with these options.
+    #[must_use]
+    pub fn selects(&self, archive_path: &Path) -> bool {
+        select_path(archive_path.to_path_buf(), self).is_some()
+    }
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self {
+            preserve_ownership: running_as_root(),
+            max_path_len: DEFAULT_MAX_PATH_LEN,
+            source: None,
+            paths: PathFilter::default(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn running_as_root() -> bool {
+    // SAFETY: `geteuid` is always successful and has no side effects
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+const fn running_as_root() -> bool {
+    false
+}
+
+async fn write_bytes<W>(writer: &mut W, bytes: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    writer.write_u64_le(bytes.len() as u64).await?;
+    writer.write_all(bytes).await
+}
+
+async fn read_bytes<R>(reader: &mut R, max_len: usize) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let len = reader.read_u64_le().await?;
+    let len = match usize::try_from(len) {
+        Ok(len) if len <= max_len => len,
+        _ => {
+            log::error!(
+                "Path of {} bytes exceeds the limit of {} bytes",
+                len,
+                max_len
+            );
+            return Err(std::io::Error::new(InvalidData, "Path is too long"));
+        }
+    };
+
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+// Paths are sent as raw bytes, so that non UTF-8 names survive the round trip
+async fn read_path<R>(reader: &mut R, max_len: usize) -> std::io::Result<PathBuf>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    path_from_bytes(read_bytes(reader, max_len).await?)
+}
+
+// Only fails where paths must be valid UTF-8
+#[cfg_attr(unix, allow(clippy::unnecessary_wraps))]
+fn path_from_bytes(bytes: Vec<u8>) -> std::io::Result<PathBuf> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+    }
+
+    #[cfg(not(unix))]
+    {
+        String::from_utf8(bytes)
+            .map(PathBuf::from)
+            .map_err(|_| std::io::Error::new(InvalidData, "Path is not valid UTF-8"))
+    }
+}
+
+/// Name of the files listing entries to exclude from the backup, with the gitignore syntax.
+/// They apply to the directory they are found in and its subdirectories.
+pub const IGNORE_FILE_NAME: &str = ".forgedignore";
+
+/// Behavior of the crawl when an entry cannot be read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CrawlMode {
+    /// Abort the backup.
+    Strict,
+    /// Skip the entry, and record the error at the end of the stream.
+    #[default]
+    BestEffort,
+}
+
+impl TryFrom<&str> for CrawlMode {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "best-effort" => Ok(Self::BestEffort),
+            _ => Err("Invalid crawl mode".to_string()),
+        }
+    }
+}
+
+/// Options controlling which entries are backed up.
+#[derive(Clone, Debug, Default)]
+pub struct CrawlOptions {
+    /// Gitignore-style patterns of the files to back up.
+    /// Everything is backed up if empty. Directories are always crawled.
+    pub include: Vec<String>,
+    /// Gitignore-style patterns of the entries to skip.
+    pub exclude: Vec<String>,
+    pub mode: CrawlMode,
+}
+
+fn build_matcher(root: &Path, patterns: &[String]) -> std::io::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .map_err(|e| std::io::Error::new(InvalidInput, e))?;
+    }
+    builder
+        .build()
+        .map_err(|e| std::io::Error::new(InvalidInput, e))
+}
+
+/// A directory to back up, whose entries are sent under its name.
+/// Entries of a source with an empty name are sent at the root of the stream.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// An entry that could not be backed up.
+#[derive(Clone, Debug)]
+pub struct CrawlError {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+/// Report of a crawl, for the entries that need attention.
+/// It is sent at the end of the stream, after the trailer tag.
+#[derive(Clone, Debug, Default)]
+pub struct CrawlSummary {
+    /// Files whose content changed while being sent, and may be inconsistent.
+    pub changed: Vec<PathBuf>,
+    /// Entries that could not be read, and are missing or incomplete.
+    pub errors: Vec<CrawlError>,
+}
+
+impl CrawlSummary {
+    pub async fn write_to<W>(&self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer.write_u64_le(self.changed.len() as u64).await?;
+        for path in &self.changed {
+            write_bytes(writer, path.as_os_str().as_encoded_bytes()).await?;
+        }
+
+        writer.write_u64_le(self.errors.len() as u64).await?;
+        for error in &self.errors {
+            write_bytes(writer, error.path.as_os_str().as_encoded_bytes()).await?;
+            write_bytes(writer, error.message.as_bytes()).await?;
+        }
+
         Ok(())
-    })
-    .await
+    }
+
+    pub async fn read_from<R>(reader: &mut R, max_path_len: usize) -> std::io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut summary = Self::default();
+
+        for _ in 0..reader.read_u64_le().await? {
+            summary.changed.push(read_path(reader, max_path_len).await?);
+        }
+
+        for _ in 0..reader.read_u64_le().await? {
+            summary.errors.push(CrawlError {
+                path: read_path(reader, max_path_len).await?,
+                message: String::from_utf8_lossy(&read_bytes(reader, max_path_len).await?)
+                    .into_owned(),
+            });
+        }
+
+        Ok(summary)
+    }
+}
+
+enum FileStatus {
+    Unchanged,
+    Changed,
+    Unreadable(std::io::Error),
+}
+
+// Exactly the size announced in the stream is sent, even if the file grows or shrinks
+// while being read or cannot be read until the end, so that following entries can still be read back.
+async fn send_file<W>(
+    file: tokio::fs::File,
+    metadata: &std::fs::Metadata,
+    tx: &mut W,
+) -> std::io::Result<FileStatus>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let file_size = metadata.len();
+    tx.write_u64_le(file_size).await?;
+
+    let mut src = tokio::io::BufReader::new(file);
+    let mut status = FileStatus::Unchanged;
+
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut bytes_left = file_size;
+    while bytes_left > 0 {
+        let bytes_to_read = usize::try_from(bytes_left).map_or(buf.len(), |n| n.min(buf.len()));
+        let bytes_read = match src.read(&mut buf[..bytes_to_read]).await {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                status = FileStatus::Unreadable(e);
+                break;
+            }
+        };
+        tx.write_all(&buf[..bytes_read]).await?;
+        bytes_left -= bytes_read as u64;
+    }
+
+    // Missing bytes are replaced with zeros
+    if bytes_left > 0 && matches!(status, FileStatus::Unchanged) {
+        status = FileStatus::Changed;
+    }
+    buf.fill(0);
+    while bytes_left > 0 {
+        let bytes_to_write = usize::try_from(bytes_left).map_or(buf.len(), |n| n.min(buf.len()));
+        tx.write_all(&buf[..bytes_to_write]).await?;
+        bytes_left -= bytes_to_write as u64;
+    }
+
+    // The file grew, or was modified in place
+    if matches!(status, FileStatus::Unchanged) {
+        let grown = matches!(src.read(&mut buf[..1]).await, Ok(n) if n > 0);
+        let modified = src.get_ref().metadata().await?.modified().ok() != metadata.modified().ok();
+        if grown || modified {
+            status = FileStatus::Changed;
+        }
+    }
+
+    tx.write_u8(u8::from(!matches!(status, FileStatus::Unchanged)))
+        .await?;
+
+    Ok(status)
+}
+
+// Data of an entry that has to be read before sending its header,
+// so that an unreadable entry can be skipped without corrupting the stream
+enum Payload {
+    File(tokio::fs::File),
+    Directory(ReadDir),
+    Symlink(PathBuf),
+    Hardlink(PathBuf),
+    Device(u64),
+    Empty,
+}
+
+struct Crawler {
+    options: CrawlOptions,
+    // Entries of the current source are sent with paths relative to `root`, under `prefix`
+    root: PathBuf,
+    prefix: PathBuf,
+    // Paths of already sent files having several links, by device and inode
+    hardlinks: HashMap<(u64, u64), PathBuf>,
+    include: Option<Gitignore>,
+    // Exclusions from the options, followed by the ones of the ignore files
+    // of the directories being crawled, from the outermost to the innermost
+    excludes: Vec<Gitignore>,
+    // Only write the paths of the entries, one per line
+    dry_run: bool,
+    summary: CrawlSummary,
+}
+
+impl Crawler {
+    fn new(options: &CrawlOptions, dry_run: bool) -> Self {
+        Self {
+            options: options.clone(),
+            root: PathBuf::new(),
+            prefix: PathBuf::new(),
+            hardlinks: HashMap::new(),
+            include: None,
+            excludes: Vec::new(),
+            dry_run,
+            summary: CrawlSummary::default(),
+        }
+    }
+
+    // Patterns are relative to the directory of each source
+    fn set_source(&mut self, source: &Source) -> std::io::Result<()> {
+        self.root.clone_from(&source.path);
+        self.prefix = PathBuf::from(&source.name);
+        self.include = if self.options.include.is_empty() {
+            None
+        } else {
+            Some(build_matcher(&self.root, &self.options.include)?)
+        };
+        self.excludes = vec![build_matcher(&self.root, &self.options.exclude)?];
+        Ok(())
+    }
+
+    fn is_selected(&self, path: &Path, is_dir: bool) -> bool {
+        // Innermost rules take precedence
+        for exclude in self.excludes.iter().rev() {
+            match exclude.matched(path, is_dir) {
+                Match::Ignore(_) => return false,
+                Match::Whitelist(_) => return true,
+                Match::None => {}
+            }
+        }
+
+        is_dir
+            || self.include.as_ref().is_none_or(|include| {
+                include
+                    .matched_path_or_any_parents(path, is_dir)
+                    .is_ignore()
+            })
+    }
+
+    async fn push_ignore_file(&mut self, dir_path: &Path) -> bool {
+        let ignore_file = dir_path.join(IGNORE_FILE_NAME);
+        if !tokio::fs::try_exists(&ignore_file).await.unwrap_or(false) {
+            return false;
+        }
+
+        let (exclude, error) = Gitignore::new(&ignore_file);
+        if let Some(e) = error {
+            log::warn!("Error in ignore file {:?}: {}", ignore_file, e);
+        }
+        self.excludes.push(exclude);
+        true
+    }
+
+    #[cfg(unix)]
+    fn hardlink_target(&mut self, path: &Path, metadata: &std::fs::Metadata) -> Option<PathBuf> {
+        use std::collections::hash_map::Entry;
+        use std::os::unix::fs::MetadataExt;
+
+        if metadata.nlink() < 2 {
+            return None;
+        }
+
+        match self.hardlinks.entry((metadata.dev(), metadata.ino())) {
+            Entry::Occupied(entry) => Some(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                entry.insert(path.to_path_buf());
+                None
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn hardlink_target(&mut self, _path: &Path, _metadata: &std::fs::Metadata) -> Option<PathBuf> {
+        None
+    }
+
+    async fn write_header<W>(
+        &self,
+        tx: &mut W,
+        kind: EntryKind,
+        archive_path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        if self.dry_run {
+            tx.write_all(archive_path.as_os_str().as_encoded_bytes())
+                .await?;
+            return tx.write_u8(b'\n').await;
+        }
+
+        tx.write_u8(kind as u8).await?;
+        write_bytes(tx, archive_path.as_os_str().as_encoded_bytes()).await?;
+        EntryMetadata::from(metadata).write_to(tx).await
+    }
+
+    // In best-effort mode, errors on the crawled entries are recorded instead of being returned
+    fn record_error(
+        &mut self,
+        archive_path: PathBuf,
+        error: std::io::Error,
+    ) -> std::io::Result<()> {
+        match self.options.mode {
+            CrawlMode::Strict => Err(error),
+            CrawlMode::BestEffort => {
+                log::warn!("Skipping {:?}: {}", archive_path, error);
+                self.summary.errors.push(CrawlError {
+                    path: archive_path,
+                    message: error.to_string(),
+                });
+                Ok(())
+            }
+        }
+    }
+
+    async fn crawl_source<W>(&mut self, source: &Source, tx: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        self.set_source(source)?;
+
+        let directory = match tokio::fs::read_dir(&source.path).await {
+            Ok(directory) => directory,
+            Err(e) => return self.record_error(self.prefix.clone(), e),
+        };
+
+        // The directory of a named source is sent too, so that its metadata is restored
+        if !source.name.is_empty() {
+            let metadata = match tokio::fs::metadata(&source.path).await {
+                Ok(metadata) => metadata,
+                Err(e) => return self.record_error(self.prefix.clone(), e),
+            };
+            self.write_header(tx, EntryKind::Directory, &self.prefix, &metadata)
+                .await?;
+        }
+
+        self.crawl_dir(&source.path, directory, tx).await
+    }
+
+    async fn payload(
+        &mut self,
+        kind: &mut EntryKind,
+        path: &Path,
+        archive_path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> std::io::Result<Payload> {
+        Ok(match kind {
+            EntryKind::File => {
+                let file = tokio::fs::File::open(path).await?;
+                // Only files that can be sent may be linked to
+                self.hardlink_target(archive_path, metadata)
+                    .map_or(Payload::File(file), |target| {
+                        *kind = EntryKind::Hardlink;
+                        Payload::Hardlink(target)
+                    })
+            }
+            EntryKind::Directory => Payload::Directory(tokio::fs::read_dir(path).await?),
+            EntryKind::Symlink => Payload::Symlink(tokio::fs::read_link(path).await?),
+            EntryKind::CharDevice | EntryKind::BlockDevice => {
+                #[cfg(unix)]
+                let rdev = std::os::unix::fs::MetadataExt::rdev(metadata);
+                #[cfg(not(unix))]
+                let rdev = 0;
+                Payload::Device(rdev)
+            }
+            EntryKind::Hardlink | EntryKind::Fifo => Payload::Empty,
+        })
+    }
+
+    async fn crawl_dir<W>(
+        &mut self,
+        dir_path: &Path,
+        mut directory: ReadDir,
+        tx: &mut W,
+    ) -> Result<(), std::io::Error>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        Box::pin(async move {
+            let has_ignore_file = self.push_ignore_file(dir_path).await;
+
+            loop {
+                let entry = match directory.next_entry().await {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.record_error(self.archive_path(dir_path), e)?;
+                        break;
+                    }
+                };
+
+                let path = entry.path();
+                let archive_path = self.archive_path(&path);
+
+                // Symlinks are not followed
+                let metadata = match entry.metadata().await {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        self.record_error(archive_path, e)?;
+                        continue;
+                    }
+                };
+
+                let Some(mut kind) = EntryKind::of(metadata.file_type()) else {
+                    log::warn!("Skipping unsupported file type: {:?}", path);
+                    continue;
+                };
+
+                if !self.is_selected(&path, kind == EntryKind::Directory) {
+                    log::trace!("Skipping excluded entry: {:?}", path);
+                    continue;
+                }
+
+                let payload = match self
+                    .payload(&mut kind, &path, &archive_path, &metadata)
+                    .await
+                {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        self.record_error(archive_path, e)?;
+                        continue;
+                    }
+                };
+
+                self.write_header(tx, kind, &archive_path, &metadata)
+                    .await?;
+                if self.dry_run && kind != EntryKind::Directory {
+                    continue;
+                }
+
+                match payload {
+                    Payload::File(file) => {
+                        log::trace!("Sending file: {:?}", path);
+                        match send_file(file, &metadata, tx).await? {
+                            FileStatus::Unchanged => {}
+                            FileStatus::Changed => {
+                                log::warn!("File changed during backup: {:?}", path);
+                                self.summary.changed.push(archive_path);
+                            }
+                            FileStatus::Unreadable(e) => {
+                                self.summary.changed.push(archive_path.clone());
+                                self.record_error(archive_path, e)?;
+                            }
+                        }
+                    }
+                    Payload::Directory(directory) => {
+                        self.crawl_dir(&path, directory, tx).await?;
+                    }
+                    Payload::Symlink(target) => {
+                        write_bytes(tx, target.as_os_str().as_encoded_bytes()).await?;
+                        log::trace!("Sending symlink: {:?} -> {:?}", path, target);
+                    }
+                    Payload::Hardlink(target) => {
+                        write_bytes(tx, target.as_os_str().as_encoded_bytes()).await?;
+                        log::trace!("Sending hardlink: {:?} -> {:?}", path, target);
+                    }
+                    Payload::Device(rdev) => tx.write_u64_le(rdev).await?,
+                    Payload::Empty => {}
+                }
+            }
+
+            if has_ignore_file {
+                self.excludes.pop();
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    fn archive_path(&self, path: &Path) -> PathBuf {
+        self.prefix.join(
+            path.strip_prefix(&self.root)
+                .expect("Entry is outside of the crawled directory"),
+        )
+    }
 }
 
 // ## Errors
 // This function returns an error if it fails to read the directory.
-pub async fn read_dir(dir_path: PathBuf, tx: &mut DuplexStream) -> Result<(), std::io::Error> {
-    let directory = tokio::fs::read_dir(dir_path).await?;
-    crawl_dir(directory, tx).await?;
+pub async fn read_dir(
+    sources: &[Source],
+    options: &CrawlOptions,
+    tx: &mut DuplexStream,
+) -> Result<CrawlSummary, std::io::Error> {
+    let mut crawler = Crawler::new(options, false);
+    for source in sources {
+        log::debug!("Sending source {:?}: {:?}", source.name, source.path);
+        crawler.crawl_source(source, tx).await?;
+    }
+
+    tx.write_u8(TRAILER_TAG).await?;
+    crawler.summary.write_to(tx).await?;
+
+    Ok(crawler.summary)
+}
+
+// Writes the paths of the entries that `read_dir` would send, one per line.
+// ## Errors
+// This function returns an error if it fails to read the directory.
+pub async fn list_dir<W>(
+    sources: &[Source],
+    options: &CrawlOptions,
+    writer: &mut W,
+) -> Result<CrawlSummary, std::io::Error>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut crawler = Crawler::new(options, true);
+    for source in sources {
+        crawler.crawl_source(source, writer).await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(crawler.summary)
+}
+
+// Archived paths must stay inside the output directory:
+// they have to be relative, without `..`, and must not go through a symlink.
+async fn resolve_path(output_path: &Path, archive_path: &Path) -> std::io::Result<PathBuf> {
+    let is_safe = archive_path.components().next().is_some()
+        && archive_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !is_safe {
+        log::error!("Refusing to restore unsafe path: {:?}", archive_path);
+        return Err(std::io::Error::new(InvalidData, "Unsafe path in archive"));
+    }
+
+    let mut path = output_path.to_path_buf();
+    for component in archive_path.parent().into_iter().flat_map(Path::components) {
+        path.push(component);
+        if let Ok(metadata) = tokio::fs::symlink_metadata(&path).await {
+            if metadata.is_symlink() {
+                log::error!("Refusing to restore through symlink: {:?}", path);
+                return Err(std::io::Error::new(
+                    InvalidData,
+                    "Archive path goes through a symlink",
+                ));
+            }
+        }
+    }
+
+    Ok(output_path.join(archive_path))
+}
+
+// Files already on disk are replaced, rather than written through if they are links
+async fn remove_existing(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if !metadata.is_dir() => tokio::fs::remove_file(path).await,
+        _ => Ok(()),
+    }
+}
+
+// Returns whether the file has been created, as creating devices is usually reserved to root
+async fn make_special_file(
+    path: &Path,
+    kind: EntryKind,
+    mode: u32,
+    rdev: u64,
+) -> std::io::Result<bool> {
+    #[cfg(unix)]
+    {
+        let node_path = path.to_path_buf();
+        let result =
+            tokio::task::spawn_blocking(move || make_node(&node_path, kind, mode, rdev)).await?;
+        match result {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                log::warn!("Skipping special file {:?}: {}", path, e);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (kind, mode, rdev);
+        log::warn!("Skipping special file: {:?}", path);
+        Ok(false)
+    }
+}
+
+#[cfg(unix)]
+fn make_node(path: &Path, kind: EntryKind, mode: u32, rdev: u64) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mode =
+        libc::mode_t::try_from(mode).map_err(|e| std::io::Error::new(InvalidData, e))? & 0o7777;
+    // The file type is given by the kind, `mode` may only hold permissions
+    let file_type = if kind == EntryKind::CharDevice {
+        libc::S_IFCHR
+    } else {
+        libc::S_IFBLK
+    };
+    // SAFETY: `path` is a valid C string
+    let result = unsafe {
+        if kind == EntryKind::Fifo {
+            libc::mkfifo(path.as_ptr(), mode)
+        } else {
+            libc::mknod(path.as_ptr(), file_type | mode, rdev)
+        }
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+// Returns the path to restore an entry to, if it is selected by the options
+fn select_path(archive_path: PathBuf, options: &RestoreOptions) -> Option<PathBuf> {
+    if !options.paths.matches(&archive_path) {
+        return None;
+    }
+
+    let Some(source) = &options.source else {
+        return Some(archive_path);
+    };
+
+    // The directory of the source is the output directory itself
+    match archive_path.strip_prefix(source) {
+        Ok(path) if path.components().next().is_some() => Some(path.to_path_buf()),
+        _ => None,
+    }
+}
+
+/// An entry of the stream, as read back.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub path: PathBuf,
+    pub metadata: EntryMetadata,
+    /// Size of the content of files, which follows the entry in the stream.
+    pub size: u64,
+    /// Target of symlinks and hardlinks.
+    pub target: Option<PathBuf>,
+    /// Device number of devices.
+    pub rdev: u64,
+}
+
+impl Entry {
+    // Writes the entry as it appears in the stream, without the content of files
+    pub async fn write_to<W>(&self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer.write_u8(self.kind as u8).await?;
+        write_bytes(writer, self.path.as_os_str().as_encoded_bytes()).await?;
+        self.metadata.write_to(writer).await?;
+
+        match self.kind {
+            EntryKind::File => writer.write_u64_le(self.size).await,
+            EntryKind::Symlink | EntryKind::Hardlink => {
+                let target = self.target.as_deref().unwrap_or_else(|| Path::new(""));
+                write_bytes(writer, target.as_os_str().as_encoded_bytes()).await
+            }
+            EntryKind::CharDevice | EntryKind::BlockDevice => writer.write_u64_le(self.rdev).await,
+            EntryKind::Directory | EntryKind::Fifo => Ok(()),
+        }
+    }
+}
+
+/// A record of the stream.
+#[derive(Clone, Debug)]
+pub enum Record {
+    Entry(Entry),
+    Trailer(CrawlSummary),
+}
+
+// Reads the next record of the stream, or `None` at the end of the stream.
+// The content of files is left in the stream, and must be consumed with `read_content` or `skip_content`.
+// ## Errors
+// This function returns an error if the stream cannot be read or is invalid.
+pub async fn read_record<R>(reader: &mut R, max_path_len: usize) -> std::io::Result<Option<Record>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let kind = match reader.read_u8().await {
+        Ok(TRAILER_TAG) => {
+            let summary = CrawlSummary::read_from(reader, max_path_len).await?;
+            return Ok(Some(Record::Trailer(summary)));
+        }
+        Ok(x) => EntryKind::try_from(x)?,
+        // Unexpected EOF means all data has been read
+        Err(e) if e.kind() == UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut entry = Entry {
+        kind,
+        path: read_path(reader, max_path_len).await?,
+        metadata: EntryMetadata::read_from(reader).await?,
+        size: 0,
+        target: None,
+        rdev: 0,
+    };
+
+    match kind {
+        EntryKind::File => entry.size = reader.read_u64_le().await?,
+        EntryKind::Symlink | EntryKind::Hardlink => {
+            entry.target = Some(read_path(reader, max_path_len).await?);
+        }
+        EntryKind::CharDevice | EntryKind::BlockDevice => entry.rdev = reader.read_u64_le().await?,
+        EntryKind::Directory | EntryKind::Fifo => {}
+    }
+
+    Ok(Some(Record::Entry(entry)))
+}
+
+// Copies the content of a file entry to `writer`.
+// Returns whether the file changed during the backup.
+// ## Errors
+// This function returns an error if the stream cannot be read or the writer cannot be written.
+pub async fn read_content<R, W>(
+    reader: &mut R,
+    entry: &Entry,
+    writer: &mut W,
+) -> std::io::Result<bool>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let copied = tokio::io::copy(&mut reader.take(entry.size), writer).await?;
+    if copied != entry.size {
+        return Err(std::io::Error::from(UnexpectedEof));
+    }
+
+    Ok(reader.read_u8().await? != 0)
+}
+
+// ## Errors
+// This function returns an error if the stream cannot be read.
+pub async fn skip_content<R>(reader: &mut R, entry: &Entry) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    if entry.kind == EntryKind::File {
+        read_content(reader, entry, &mut tokio::io::sink()).await?;
+    }
 
     Ok(())
 }
 
+// Walks the stream without restoring anything, calling `f` on every entry.
+// Errors returned by `f` stop the walk.
+// Returns the trailer of the stream, if any.
 // ## Errors
-// This function returns an error if it fails to write the directory.
-pub async fn write_dir(
-    reader: &mut DuplexStream,
-    output_path: PathBuf,
-) -> Result<(), std::io::Error> {
-    let mut buf = [0; BUFFER_SIZE];
-    let mut file_path = [0u8; 260]; // Filepath is at most 260 ASCII chars
+// This function returns an error if the stream cannot be read or is invalid.
+pub async fn walk<R, F>(
+    reader: &mut R,
+    max_path_len: usize,
+    mut f: F,
+) -> std::io::Result<Option<CrawlSummary>>
+where
+    R: AsyncRead + Unpin + Send,
+    F: FnMut(&Entry) -> std::io::Result<()> + Send,
+{
+    while let Some(record) = read_record(reader, max_path_len).await? {
+        match record {
+            Record::Entry(entry) => {
+                f(&entry)?;
+                skip_content(reader, &entry).await?;
+            }
+            Record::Trailer(summary) => return Ok(Some(summary)),
+        }
+    }
 
-    loop {
-        let result = reader.read_u64_le().await;
+    Ok(None)
+}
+
+// Converts the stream of a legacy archive, written before entries had metadata,
+// into the current stream, ending with an empty trailer.
+// Legacy records are made of the length of the path of a file, its path, its size and its content,
+// with paths as seen by the client. Files get `timestamp` as times, as they were not recorded.
+// ## Errors
+// This function returns an error if the legacy stream cannot be read or is invalid,
+// or if the stream cannot be written.
+pub async fn upgrade_legacy_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    timestamp: Timestamp,
+    max_path_len: usize,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let metadata = EntryMetadata {
+        mode: 0o644,
+        uid: 0,
+        gid: 0,
+        atime: timestamp,
+        mtime: timestamp,
+        ctime: timestamp,
+    };
 
-        let file_path_len = usize::try_from(match result {
-            Ok(x) => x,
-            // Unexpected EOF means all data has been read
+    loop {
+        // The stream ends after the last record, without any trailer
+        let path_len = match reader.read_u64_le().await {
+            Ok(path_len) => path_len,
             Err(e) if e.kind() == UnexpectedEof => break,
             Err(e) => return Err(e),
-        })
-        .expect("Size is too big");
+        };
+        let path_len = match usize::try_from(path_len) {
+            Ok(path_len) if path_len <= max_path_len => path_len,
+            _ => return Err(std::io::Error::new(InvalidData, "Path is too long")),
+        };
+        let mut path = vec![0; path_len];
+        reader.read_exact(&mut path).await?;
+
+        // Paths were those of the client, they are restored relatively to the output directory
+        let path = path_from_bytes(path)?
+            .components()
+            .filter(|component| !matches!(component, Component::RootDir | Component::Prefix(_)))
+            .collect();
+
+        let entry = Entry {
+            kind: EntryKind::File,
+            path,
+            metadata,
+            size: reader.read_u64_le().await?,
+            target: None,
+            rdev: 0,
+        };
+        entry.write_to(writer).await?;
+
+        let copied = tokio::io::copy(&mut (&mut *reader).take(entry.size), writer).await?;
+        if copied != entry.size {
+            return Err(std::io::Error::from(UnexpectedEof));
+        }
+        writer.write_u8(0).await?;
+    }
+
+    writer.write_u8(TRAILER_TAG).await?;
+    CrawlSummary::default().write_to(writer).await?;
+    writer.flush().await
+}
+
+/// Extensions of files whose content is not worth compressing, by default.
+pub const DEFAULT_INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "mp3", "mp4", "mkv", "webm", "zip", "gz", "tgz", "bz2",
+    "xz", "zst", "lz4", "7z",
+];
+
+// Smaller files are compressed along with their neighbours, as blocks of their own would not be worth it
+const MIN_INCOMPRESSIBLE_SIZE: u64 = 1 << 16;
+
+// Keeps a copy of the bytes read, to forward records as they are
+struct TeeReader<'a, R> {
+    reader: &'a mut R,
+    copy: Vec<u8>,
+}
+
+impl<R> AsyncRead for TeeReader<'_, R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut *self.reader).poll_read(cx, buf))?;
+        self.copy.extend_from_slice(&buf.filled()[filled..]);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Sends the stream to `chunks` as it is, in chunks of at most `chunk_size` bytes.
+// The content of large files with one of `incompressible_extensions` is marked as incompressible.
+// Invalid records are not an error, as hints are only an optimization: the rest of the stream is then sent as is.
+// ## Errors
+// This function returns an error if the stream cannot be read or the channel is closed.
+pub async fn hint_stream<R>(
+    reader: &mut R,
+    chunks: &mpsc::Sender<Chunk>,
+    incompressible_extensions: &[String],
+    chunk_size: usize,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let send = |data: Vec<u8>, compressible: bool| async move {
+        chunks
+            .send(Chunk { data, compressible })
+            .await
+            .map_err(|_| std::io::Error::from(BrokenPipe))
+    };
+
+    let is_incompressible = |entry: &Entry| {
+        entry.kind == EntryKind::File
+            && entry.size >= MIN_INCOMPRESSIBLE_SIZE
+            && entry.path.extension().is_some_and(|extension| {
+                incompressible_extensions
+                    .iter()
+                    .any(|incompressible| extension.eq_ignore_ascii_case(incompressible))
+            })
+    };
+
+    let mut tee = TeeReader {
+        reader,
+        copy: Vec::new(),
+    };
+
+    loop {
+        let record = read_record(&mut tee, DEFAULT_MAX_PATH_LEN).await;
+        send(std::mem::take(&mut tee.copy), true).await?;
+
+        let entry = match record {
+            Ok(Some(Record::Entry(entry))) if entry.kind == EntryKind::File => entry,
+            Ok(Some(Record::Entry(_))) => continue,
+            Ok(Some(Record::Trailer(_)) | None) | Err(_) => break,
+        };
+
+        let compressible = !is_incompressible(&entry);
+        let mut content = (&mut *tee.reader).take(entry.size);
+        loop {
+            let mut data = vec![0u8; chunk_size];
+            let bytes_read = content.read(&mut data).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            data.truncate(bytes_read);
+            send(data, compressible).await?;
+        }
+
+        // The status of the file is sent along with the next record
+        if let Err(e) = tee.read_u8().await {
+            if e.kind() == UnexpectedEof {
+                break;
+            }
+            return Err(e);
+        }
+    }
+
+    // Anything left, in case of an invalid record
+    loop {
+        let mut data = vec![0u8; chunk_size];
+        let bytes_read = tee.reader.read(&mut data).await?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        data.truncate(bytes_read);
+        send(data, true).await?;
+    }
+}
+
+/// Hardlinks selected by restore options whose target is not selected.
+/// The content of such a target is restored to the first selected link instead,
+/// and the other selected links point to it.
+#[derive(Debug, Default)]
+pub struct DetachedLinks {
+    // First selected link of each target, as restored, and whether the target has been written to it
+    first_links: HashMap<PathBuf, (PathBuf, bool)>,
+}
+
+impl DetachedLinks {
+    /// Records `entry` if it is a detached link, entries being given in the order of the stream.
+    pub fn add(&mut self, entry: &Entry, options: &RestoreOptions) {
+        let Some(target) = entry
+            .target
+            .as_ref()
+            .filter(|_| entry.kind == EntryKind::Hardlink)
+        else {
+            return;
+        };
+        if options.selects(target) {
+            return;
+        }
+        if let Some(path) = select_path(entry.path.clone(), options) {
+            self.first_links
+                .entry(target.clone())
+                .or_insert((path, false));
+        }
+    }
+
+    /// Whether `archive_path` is the target of a detached link.
+    #[must_use]
+    pub fn is_target(&self, archive_path: &Path) -> bool {
+        self.first_links.contains_key(archive_path)
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.first_links.is_empty()
+    }
+}
+
+struct Restorer<'a> {
+    output_path: &'a Path,
+    options: &'a RestoreOptions,
+    links: DetachedLinks,
+    // Directories metadata is applied last, as writing their content changes their timestamps
+    directories: Vec<(PathBuf, EntryMetadata)>,
+}
 
-        reader.read_exact(&mut file_path[..file_path_len]).await?;
-        let file_path = output_path.join(std::str::from_utf8(&file_path[..file_path_len]).unwrap());
+impl<'a> Restorer<'a> {
+    const fn new(output_path: &'a Path, options: &'a RestoreOptions, links: DetachedLinks) -> Self {
+        Self {
+            output_path,
+            options,
+            links,
+            directories: Vec::new(),
+        }
+    }
+
+    // Returns whether the entry is selected by the options and has been written
+    async fn restore<R>(&mut self, reader: &mut R, entry: Entry) -> std::io::Result<bool>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        if let Some(path) = select_path(entry.path.clone(), self.options) {
+            return self.restore_at(reader, entry, &path).await;
+        }
+
+        // The target of detached links is written to the first of them, which is counted once reached
+        if entry.kind == EntryKind::File {
+            if let Some((path, written)) = self.links.first_links.get_mut(&entry.path) {
+                *written = true;
+                let path = path.clone();
+                self.restore_at(reader, entry, &path).await?;
+                return Ok(false);
+            }
+        }
 
-        let file_size = usize::try_from(reader.read_u64_le().await?).expect("Size is too big");
+        skip_content(reader, &entry).await?;
+        Ok(false)
+    }
+
+    // Restores the entry to `path`, relative to the output directory.
+    // Returns whether the entry has been written.
+    async fn restore_at<R>(
+        &mut self,
+        reader: &mut R,
+        entry: Entry,
+        path: &Path,
+    ) -> std::io::Result<bool>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let file_path = resolve_path(self.output_path, path).await?;
+
+        // Resolved before anything is removed, as a detached link may already hold its target
+        let link_target = if entry.kind == EntryKind::Hardlink {
+            let target = entry.target.clone().unwrap_or_default();
+            match select_path(target.clone(), self.options) {
+                Some(target) => Some(target),
+                None => match self.links.first_links.get(&target) {
+                    Some((first, true)) if first == path => {
+                        log::trace!("Wrote hardlink target to its link: {:?}", file_path);
+                        return Ok(true);
+                    }
+                    Some((first, true)) => Some(first.clone()),
+                    _ => {
+                        log::warn!(
+                            "Skipping hardlink to an entry not restored: {:?}",
+                            file_path
+                        );
+                        return Ok(false);
+                    }
+                },
+            }
+        } else {
+            None
+        };
 
         tokio::fs::create_dir_all(file_path.parent().unwrap()).await?;
-        let file = tokio::fs::File::create(&file_path).await?;
-        let mut writer = tokio::io::BufWriter::new(file);
+        // Symlinks are replaced by directories as well, so that they are not written through
+        remove_existing(&file_path).await?;
 
-        let mut bytes_left = file_size;
-        while bytes_left > 0 {
-            let bytes_to_read = bytes_left.min(BUFFER_SIZE);
-            let bytes_read = reader.read(&mut buf[..bytes_to_read]).await?;
-            writer.write_all(&buf[..bytes_read]).await?;
-            bytes_left -= bytes_read;
+        match entry.kind {
+            EntryKind::File => {
+                let file = tokio::fs::File::create(&file_path).await?;
+                let mut writer = tokio::io::BufWriter::with_capacity(BUFFER_SIZE, file);
+                let changed = read_content(reader, &entry, &mut writer).await?;
+                writer.flush().await?;
+                if changed {
+                    log::warn!(
+                        "File changed during backup, its content may be inconsistent: {:?}",
+                        file_path
+                    );
+                }
+            }
+            EntryKind::Directory => {
+                tokio::fs::create_dir_all(&file_path).await?;
+                log::trace!("Created directory: {:?}", file_path);
+                self.directories.push((file_path, entry.metadata));
+                return Ok(true);
+            }
+            EntryKind::Symlink => {
+                let target = entry.target.clone().unwrap_or_default();
+                #[cfg(unix)]
+                tokio::fs::symlink(target, &file_path).await?;
+                #[cfg(not(unix))]
+                {
+                    let _ = target;
+                    log::warn!("Skipping symlink: {:?}", file_path);
+                    return Ok(false);
+                }
+            }
+            EntryKind::Hardlink => {
+                let target =
+                    resolve_path(self.output_path, &link_target.unwrap_or_default()).await?;
+                tokio::fs::hard_link(&target, &file_path).await?;
+                // Metadata is shared with the target, which has already been restored
+                log::trace!("Wrote hardlink: {:?}", file_path);
+                return Ok(true);
+            }
+            EntryKind::Fifo | EntryKind::CharDevice | EntryKind::BlockDevice => {
+                if !make_special_file(&file_path, entry.kind, entry.metadata.mode, entry.rdev)
+                    .await?
+                {
+                    return Ok(false);
+                }
+            }
         }
 
-        writer.flush().await?;
+        let (path, kind, metadata) = (file_path.clone(), entry.kind, entry.metadata);
+        let preserve_ownership = self.options.preserve_ownership;
+        tokio::task::spawn_blocking(move || metadata.apply(&path, kind, preserve_ownership))
+            .await??;
+
+        log::trace!("Wrote {:?}: {:?}", kind, file_path);
+
+        Ok(true)
+    }
+
+    async fn finish(self) -> std::io::Result<()> {
+        let preserve_ownership = self.options.preserve_ownership;
+        for (path, metadata) in self.directories.into_iter().rev() {
+            tokio::task::spawn_blocking(move || {
+                metadata.apply(&path, EntryKind::Directory, preserve_ownership)
+            })
+            .await??;
+        }
 
-        log::trace!("Wrote file: {:?}", file_path);
+        Ok(())
     }
+}
 
+// ## Errors
+// This function returns an error if it fails to write the directory.
+pub async fn write_dir<R>(
+    reader: &mut R,
+    output_path: PathBuf,
+    options: &RestoreOptions,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    write_selected(reader, output_path, options, DetachedLinks::default(), None).await?;
     Ok(())
 }
+
+// Restores the entries selected by the options, stopping after `limit` of them if any.
+// The targets of `links` are restored to their first selected link, so they must be read from the stream
+// when the stream does not start at its beginning.
+// Returns the number of restored entries.
+// ## Errors
+// This function returns an error if it fails to write the entries.
+pub async fn write_selected<R>(
+    reader: &mut R,
+    output_path: PathBuf,
+    options: &RestoreOptions,
+    links: DetachedLinks,
+    limit: Option<usize>,
+) -> Result<usize, std::io::Error>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut restorer = Restorer::new(&output_path, options, links);
+    let mut count = 0;
+
+    while limit.is_none_or(|limit| count < limit) {
+        let Some(record) = read_record(reader, options.max_path_len).await? else {
+            break;
+        };
+
+        match record {
+            Record::Entry(entry) => {
+                if restorer.restore(reader, entry).await? {
+                    count += 1;
+                }
+            }
+            Record::Trailer(summary) => {
+                for error in &summary.errors {
+                    log::warn!(
+                        "Entry could not be backed up: {:?}: {}",
+                        error.path,
+                        error.message
+                    );
+                }
+                break;
+            }
+        }
+    }
+
+    restorer.finish().await?;
+    Ok(count)
+}
+
+// Restores the single entry at the current position of the stream to `path`, as if it were in the archive there.
+// This differs from the path of the entry when extracting the target of a hardlink to the hardlink.
+// Returns the restored entry, or `None` if there is no more entries.
+// ## Errors
+// This function returns an error if it fails to write the entry.
+pub async fn write_entry<R>(
+    reader: &mut R,
+    path: &Path,
+    output_path: PathBuf,
+    options: &RestoreOptions,
+) -> Result<Option<Entry>, std::io::Error>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let Some(Record::Entry(entry)) = read_record(reader, options.max_path_len).await? else {
+        return Ok(None);
+    };
+
+    let mut restorer = Restorer::new(&output_path, options, DetachedLinks::default());
+    match select_path(path.to_path_buf(), options) {
+        Some(path) => {
+            restorer.restore_at(reader, entry.clone(), &path).await?;
+        }
+        None => skip_content(reader, &entry).await?,
+    }
+    restorer.finish().await?;
+
+    Ok(Some(entry))
+}
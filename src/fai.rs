@@ -0,0 +1,336 @@
+//! Forged Archive Index (fAI)
+
+use std::io::ErrorKind::{BrokenPipe, UnexpectedEof};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{
+    duplex, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+    DuplexStream, ReadBuf,
+};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::fadc::{
+    self, CrawlSummary, DetachedLinks, Entry, EntryKind, Record, RestoreOptions, TRAILER_TAG,
+};
+use crate::fce::{self, Block};
+use crate::DUPLEX_BUFFER_SIZE;
+
+/// Extension of the index written alongside each archive.
+pub const INDEX_EXTENSION: &str = "idx";
+
+/// Position of an entry in an archive, ordered as in the archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    /// Offset of the block containing the start of the entry, from the end of the archive header.
+    pub block_offset: u64,
+    /// Offset of the entry in the uncompressed block.
+    pub offset: u64,
+}
+
+/// An entry of the index.
+#[derive(Clone, Debug)]
+pub struct IndexEntry {
+    pub entry: Entry,
+    pub position: Position,
+}
+
+// Reads the uncompressed stream from the blocks sent by the compression engine
+struct BlockReader {
+    blocks: mpsc::Receiver<Block>,
+    block: Block,
+    position: usize,
+}
+
+impl BlockReader {
+    fn new(blocks: mpsc::Receiver<Block>) -> Self {
+        Self {
+            blocks,
+            block: Block::default(),
+            position: 0,
+        }
+    }
+
+    // Returns the position of the next byte, or `None` at the end of the stream
+    async fn next_position(&mut self) -> Option<Position> {
+        while self.position == self.block.data.len() {
+            self.block = self.blocks.recv().await?;
+            self.position = 0;
+        }
+
+        Some(Position {
+            block_offset: self.block.offset,
+            offset: self.position as u64,
+        })
+    }
+}
+
+impl AsyncRead for BlockReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.position == self.block.data.len() {
+            match ready!(self.blocks.poll_recv(cx)) {
+                Some(block) => {
+                    self.block = block;
+                    self.position = 0;
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = buf.remaining().min(self.block.data.len() - self.position);
+        buf.put_slice(&self.block.data[self.position..self.position + len]);
+        self.position += len;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Writes the index of the archive whose uncompressed blocks are received from `blocks`.
+// The index is made of the entries of the stream, each followed by its position, and of the trailer.
+// ## Errors
+// This function returns an error if the stream is invalid or the index cannot be written.
+pub async fn index_stream<W>(blocks: mpsc::Receiver<Block>, writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut reader = BlockReader::new(blocks);
+
+    while let Some(position) = reader.next_position().await {
+        match fadc::read_record(&mut reader, fadc::DEFAULT_MAX_PATH_LEN).await? {
+            Some(Record::Entry(entry)) => {
+                entry.write_to(writer).await?;
+                writer.write_u64_le(position.block_offset).await?;
+                writer.write_u64_le(position.offset).await?;
+                fadc::skip_content(&mut reader, &entry).await?;
+            }
+            Some(Record::Trailer(summary)) => {
+                writer.write_u8(TRAILER_TAG).await?;
+                summary.write_to(writer).await?;
+                break;
+            }
+            None => break,
+        }
+    }
+
+    writer.flush().await
+}
+
+/// A record of the index.
+#[derive(Clone, Debug)]
+pub enum IndexRecord {
+    Entry(IndexEntry),
+    Trailer(CrawlSummary),
+}
+
+// Reads the next record of the index, or `None` at the end of the index.
+// ## Errors
+// This function returns an error if the index cannot be read or is invalid.
+pub async fn read_record<R>(
+    reader: &mut R,
+    max_path_len: usize,
+) -> std::io::Result<Option<IndexRecord>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    Ok(match fadc::read_record(reader, max_path_len).await? {
+        Some(Record::Entry(entry)) => {
+            let position = Position {
+                block_offset: reader.read_u64_le().await?,
+                offset: reader.read_u64_le().await?,
+            };
+            Some(IndexRecord::Entry(IndexEntry { entry, position }))
+        }
+        Some(Record::Trailer(summary)) => Some(IndexRecord::Trailer(summary)),
+        None => None,
+    })
+}
+
+// Walks the index, calling `f` on every entry.
+// Errors returned by `f` stop the walk.
+// Returns the trailer of the index, if any.
+// ## Errors
+// This function returns an error if the index cannot be read or is invalid.
+pub async fn walk<R, F>(
+    reader: &mut R,
+    max_path_len: usize,
+    mut f: F,
+) -> std::io::Result<Option<CrawlSummary>>
+where
+    R: AsyncRead + Unpin + Send,
+    F: FnMut(&IndexEntry) -> std::io::Result<()> + Send,
+{
+    while let Some(record) = read_record(reader, max_path_len).await? {
+        match record {
+            IndexRecord::Entry(entry) => f(&entry)?,
+            IndexRecord::Trailer(summary) => return Ok(Some(summary)),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns the path of the index of an archive.
+#[must_use]
+pub fn index_path(archive_path: &Path) -> PathBuf {
+    archive_path.with_extension(INDEX_EXTENSION)
+}
+
+// Decompresses the archive from `position`, without decompressing the blocks before it
+async fn decompress_from(
+    archive_path: &Path,
+    position: Position,
+) -> std::io::Result<(DuplexStream, JoinHandle<std::io::Result<()>>)> {
+    let (header, mut archive) = fce::open_archive(archive_path).await?;
+    let block_offset = i64::try_from(position.block_offset)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    archive.seek(SeekFrom::Current(block_offset)).await?;
+
+    let (mut tx, mut rx) = duplex(DUPLEX_BUFFER_SIZE);
+
+    let decompress_handle = tokio::spawn(async move {
+        fce::decompress_stream_from(&mut archive, &mut tx, &header, position.block_offset).await
+    });
+
+    let skipped =
+        tokio::io::copy(&mut (&mut rx).take(position.offset), &mut tokio::io::sink()).await?;
+    if skipped != position.offset {
+        return Err(std::io::Error::from(UnexpectedEof));
+    }
+
+    Ok((rx, decompress_handle))
+}
+
+// The rest of the archive may not be needed, in which case decompression is interrupted
+async fn stop_decompression(
+    rx: DuplexStream,
+    decompress_handle: JoinHandle<std::io::Result<()>>,
+) -> std::io::Result<()> {
+    drop(rx);
+    match decompress_handle.await? {
+        Err(e) if e.kind() != BrokenPipe => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Finds the entry with the given path in the index, reading it from its current position.
+// ## Errors
+// This function returns an error if the index cannot be read or is invalid.
+pub async fn find<R>(
+    reader: &mut R,
+    path: &Path,
+    max_path_len: usize,
+) -> std::io::Result<Option<IndexEntry>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    while let Some(IndexRecord::Entry(entry)) = read_record(reader, max_path_len).await? {
+        if entry.entry.path == path {
+            return Ok(Some(entry));
+        }
+    }
+
+    Ok(None)
+}
+
+// Restores the entry of the archive with the given path, found in its index.
+// Hardlinks are restored as regular files, with the content of their target.
+// Returns the entry, or `None` if there is no entry with this path.
+// ## Errors
+// This function returns an error if the index or the archive cannot be read or the entry cannot be written.
+pub async fn extract<R>(
+    archive_path: &Path,
+    index: &mut R,
+    path: &Path,
+    output_path: PathBuf,
+    options: &RestoreOptions,
+) -> std::io::Result<Option<Entry>>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send,
+{
+    let Some(found) = find(index, path, options.max_path_len).await? else {
+        return Ok(None);
+    };
+
+    // Targets come before their links in the archive
+    let mut target = found.clone();
+    while target.entry.kind == EntryKind::Hardlink {
+        let target_path = target.entry.target.clone().unwrap_or_default();
+        index.rewind().await?;
+        target = find(index, &target_path, options.max_path_len)
+            .await?
+            .filter(|entry| entry.position < target.position)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Hardlink target not found in archive: {}",
+                        target_path.display()
+                    ),
+                )
+            })?;
+    }
+
+    let (mut rx, decompress_handle) = decompress_from(archive_path, target.position).await?;
+    fadc::write_entry(&mut rx, path, output_path, options).await?;
+    stop_decompression(rx, decompress_handle).await?;
+
+    Ok(Some(found.entry))
+}
+
+// Restores the entries selected by the options, using the index to skip the archive before the first of them
+// and to stop after the last of them.
+// Selected hardlinks to a file that is not selected get a copy of the file, read from its own position.
+// Returns the number of restored entries.
+// ## Errors
+// This function returns an error if the index or the archive cannot be read or an entry cannot be written.
+pub async fn restore<R>(
+    archive_path: &Path,
+    index: &mut R,
+    output_path: PathBuf,
+    options: &RestoreOptions,
+) -> std::io::Result<usize>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send,
+{
+    let mut first = None;
+    let mut selected = 0;
+    let mut links = DetachedLinks::default();
+    walk(index, options.max_path_len, |entry| {
+        if options.selects(&entry.entry.path) {
+            first.get_or_insert(entry.position);
+            selected += 1;
+        }
+        links.add(&entry.entry, options);
+        Ok(())
+    })
+    .await?;
+
+    let Some(mut first) = first else {
+        return Ok(0);
+    };
+
+    // Targets come before their links, possibly before the first selected entry
+    if !links.is_empty() {
+        index.rewind().await?;
+        walk(index, options.max_path_len, |entry| {
+            if links.is_target(&entry.entry.path) {
+                first = first.min(entry.position);
+            }
+            Ok(())
+        })
+        .await?;
+    }
+
+    let (mut rx, decompress_handle) = decompress_from(archive_path, first).await?;
+    let count = fadc::write_selected(&mut rx, output_path, options, links, Some(selected)).await?;
+    stop_decompression(rx, decompress_handle).await?;
+
+    Ok(count)
+}
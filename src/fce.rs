@@ -1,63 +1,818 @@
 //! Forged Compression Engine (fCE)
 
-use lz4_flex::block::{compress, decompress};
-use std::io::ErrorKind::UnexpectedEof;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use lz4_flex::block::{compress, decompress, get_maximum_output_size};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+use std::io::SeekFrom;
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-use crate::BUFFER_SIZE;
+use crate::MAX_BLOCK_SIZE;
 
-pub async fn compress_stream<R, W>(reader: &mut R, writer: &mut W) -> std::io::Result<()>
+/// Magic bytes starting every archive.
+pub const MAGIC: &[u8; 8] = b"FORGEDBK";
+/// Version of the archive format, covering the header, the blocks and the fADC records.
+/// Archives written before the header was introduced are version 0.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Extension of archives, whatever their codec.
+pub const ARCHIVE_EXTENSION: &str = "fbk";
+/// Extension of archives written when LZ4 was the only codec.
+pub const LEGACY_ARCHIVE_EXTENSION: &str = "lz4";
+
+// The block is stored uncompressed
+const BLOCK_RAW: u8 = 1 << 0;
+
+// Length of a block marking the end of the blocks, followed by the digest of the archive
+const END_OF_BLOCKS: u64 = 0;
+const DIGEST_SIZE: usize = 32;
+
+// Same as the DNS limit
+const MAX_HOSTNAME_LEN: usize = 255;
+
+/// The archive was imported instead of being sent by a client.
+pub const FLAG_IMPORTED: u32 = 1 << 0;
+
+/// Compression codec of the blocks of an archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Codec {
+    Lz4 = 0,
+    /// High compression variant of LZ4, producing regular LZ4 blocks.
+    Lz4Hc = 1,
+    Zstd = 2,
+    /// Blocks are stored uncompressed, for already compressed data.
+    None = 3,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = std::io::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Lz4),
+            1 => Ok(Self::Lz4Hc),
+            2 => Ok(Self::Zstd),
+            3 => Ok(Self::None),
+            _ => Err(std::io::Error::new(
+                InvalidData,
+                format!("Unknown compression codec: {value}"),
+            )),
+        }
+    }
+}
+
+impl Codec {
+    // Levels accepted by the codec, along with the default one
+    const fn levels(self) -> (RangeInclusive<i8>, i8) {
+        match self {
+            Self::Lz4 | Self::None => (0..=0, 0),
+            Self::Lz4Hc => (1..=12, 9),
+            Self::Zstd => (1..=22, 3),
+        }
+    }
+
+    // Upper bound of the size of a compressed block
+    fn max_compressed_size(self, block_size: usize) -> usize {
+        match self {
+            Self::Lz4 | Self::Lz4Hc => get_maximum_output_size(block_size),
+            Self::Zstd => zstd::zstd_safe::compress_bound(block_size),
+            Self::None => block_size,
+        }
+    }
+}
+
+/// Codec and level used to compress an archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: i8,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            codec: Codec::Lz4,
+            level: 0,
+        }
+    }
+}
+
+impl TryFrom<&str> for Compression {
+    type Error = String;
+
+    // Either `none`, `lz4`, `lz4-hc[:level]` or `zstd[:level]`
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+
+        let codec = match name {
+            "none" => Codec::None,
+            "lz4" => Codec::Lz4,
+            "lz4-hc" => Codec::Lz4Hc,
+            "zstd" => Codec::Zstd,
+            _ => return Err(format!("Invalid compression codec: {name}")),
+        };
+
+        let (levels, default_level) = codec.levels();
+        let level = match level {
+            Some(level) => level
+                .parse::<i8>()
+                .ok()
+                .filter(|level| levels.contains(level))
+                .ok_or_else(|| format!("Invalid compression level for {name}: {level}"))?,
+            None => default_level,
+        };
+
+        Ok(Self { codec, level })
+    }
+}
+
+fn compress_block(data: &[u8], compression: Compression) -> std::io::Result<Vec<u8>> {
+    match compression.codec {
+        Codec::Lz4 => Ok(compress(data)),
+        Codec::Lz4Hc => lz4::block::compress(
+            data,
+            Some(lz4::block::CompressionMode::HIGHCOMPRESSION(i32::from(
+                compression.level,
+            ))),
+            false,
+        ),
+        Codec::Zstd => zstd::bulk::compress(data, i32::from(compression.level)),
+        Codec::None => Ok(data.to_vec()),
+    }
+}
+
+fn decompress_block(data: &[u8], codec: Codec, block_size: usize) -> std::io::Result<Vec<u8>> {
+    match codec {
+        Codec::Lz4 | Codec::Lz4Hc => decompress(data, block_size).map_err(|e| {
+            log::error!("Decompression failed: {}", e);
+            std::io::Error::new(InvalidData, "Decompression failed")
+        }),
+        Codec::Zstd => zstd::bulk::decompress(data, block_size).map_err(|e| {
+            log::error!("Decompression failed: {}", e);
+            std::io::Error::new(InvalidData, "Decompression failed")
+        }),
+        Codec::None => Ok(data.to_vec()),
+    }
+}
+
+/// Header written at the start of every archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveHeader {
+    pub version: u16,
+    pub compression: Compression,
+    /// Maximum size of the uncompressed content of a block.
+    pub block_size: u32,
+    pub flags: u32,
+    /// Time of the backup, in seconds relative to the Unix epoch.
+    pub timestamp: i64,
+    /// Hostname of the backed up client.
+    pub hostname: String,
+}
+
+impl ArchiveHeader {
+    #[must_use]
+    pub fn new(hostname: &str, timestamp: i64, compression: Compression, block_size: u32) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            compression,
+            block_size,
+            flags: 0,
+            timestamp,
+            hostname: hostname.to_string(),
+        }
+    }
+
+    // Archives without header only hold LZ4 blocks of 32 KiB
+    const fn legacy() -> Self {
+        Self {
+            version: 0,
+            compression: Compression {
+                codec: Codec::Lz4,
+                level: 0,
+            },
+            block_size: 1 << 15,
+            flags: 0,
+            timestamp: 0,
+            hostname: String::new(),
+        }
+    }
+
+    /// Whether the archive was written before archives had a header,
+    /// in which case it holds the stream of files of that time.
+    #[must_use]
+    pub const fn is_legacy(&self) -> bool {
+        self.version == 0
+    }
+
+    pub async fn write_to<W>(&self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer.write_all(MAGIC).await?;
+        writer.write_u16_le(self.version).await?;
+        writer.write_u8(self.compression.codec as u8).await?;
+        writer.write_i8(self.compression.level).await?;
+        writer.write_u32_le(self.block_size).await?;
+        writer.write_u32_le(self.flags).await?;
+        writer.write_i64_le(self.timestamp).await?;
+        writer
+            .write_u8(u8::try_from(self.hostname.len()).expect("Hostname is too long"))
+            .await?;
+        writer.write_all(self.hostname.as_bytes()).await
+    }
+
+    // Reads the header following the magic bytes
+    async fn read_after_magic<R>(reader: &mut R) -> std::io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let version = reader.read_u16_le().await?;
+        if version != FORMAT_VERSION {
+            return Err(std::io::Error::new(
+                InvalidData,
+                format!(
+                    "Unsupported archive format version {version}, this version of ForgedBackup reads version {FORMAT_VERSION}"
+                ),
+            ));
+        }
+
+        let codec = Codec::try_from(reader.read_u8().await?)?;
+        let level = reader.read_i8().await?;
+
+        // Larger blocks are considered corrupted
+        let block_size = reader.read_u32_le().await?;
+        if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+            return Err(std::io::Error::new(
+                InvalidData,
+                format!("Invalid block size: {block_size}"),
+            ));
+        }
+
+        let flags = reader.read_u32_le().await?;
+        let timestamp = reader.read_i64_le().await?;
+
+        let mut hostname = vec![0u8; MAX_HOSTNAME_LEN];
+        let hostname_len = usize::from(reader.read_u8().await?);
+        reader.read_exact(&mut hostname[..hostname_len]).await?;
+        hostname.truncate(hostname_len);
+
+        Ok(Self {
+            version,
+            compression: Compression { codec, level },
+            block_size,
+            flags,
+            timestamp,
+            hostname: String::from_utf8_lossy(&hostname).into_owned(),
+        })
+    }
+
+    // ## Errors
+    // This function returns an error if the header is missing, invalid or of an unsupported version.
+    pub async fn read_from<R>(reader: &mut R) -> std::io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(
+                InvalidData,
+                "Not a ForgedBackup archive",
+            ));
+        }
+
+        Self::read_after_magic(reader).await
+    }
+}
+
+// Opens an archive, positioned at its first block.
+// Archives written before the header was introduced are supported.
+// ## Errors
+// This function returns an error if the archive cannot be read or has an unsupported version.
+pub async fn open_archive(path: &Path) -> std::io::Result<(ArchiveHeader, BufReader<File>)> {
+    let mut reader = BufReader::new(File::open(path).await?);
+
+    let mut magic = [0u8; MAGIC.len()];
+    let header = match reader.read_exact(&mut magic).await {
+        Ok(_) if &magic == MAGIC => ArchiveHeader::read_after_magic(&mut reader).await?,
+        // Legacy archives directly start with the length of the first block
+        Ok(_) | Err(_) => {
+            reader.seek(SeekFrom::Start(0)).await?;
+            ArchiveHeader::legacy()
+        }
+    };
+
+    Ok((header, reader))
+}
+
+/// Uncompressed content of a block, along with its offset in the compressed stream.
+#[derive(Default)]
+pub struct Block {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// Part of a stream to compress.
+pub struct Chunk {
+    pub data: Vec<u8>,
+    /// Incompressible chunks are stored in blocks of their own, without trying to compress them.
+    pub compressible: bool,
+}
+
+/// Number of blocks compressed in parallel for an archive, by default.
+#[must_use]
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+// A block ready to be written
+struct CompressedBlock {
+    data: Vec<u8>,
+    // `None` if the block is stored uncompressed
+    compressed: Option<Vec<u8>>,
+    checksum: u32,
+}
+
+impl CompressedBlock {
+    fn new(data: Vec<u8>, compressible: bool, compression: Compression) -> std::io::Result<Self> {
+        let compressed = if compressible {
+            Some(compress_block(&data, compression)?)
+        } else {
+            None
+        };
+
+        // Blocks that compression does not shrink are stored as they are
+        let compressed = compressed.filter(|compressed| compressed.len() < data.len());
+        let checksum = crc32fast::hash(&data);
+
+        Ok(Self {
+            data,
+            compressed,
+            checksum,
+        })
+    }
+}
+
+// Writes the blocks of an archive, keeping track of its digest.
+// Blocks are compressed by up to `workers` blocking tasks, and written in order.
+// With no workers, they are compressed in the task writing them.
+struct BlockWriter<'a, W> {
+    writer: &'a mut W,
+    header: &'a ArchiveHeader,
+    blocks: Option<&'a mpsc::Sender<Block>>,
+    digest: Sha256,
+    offset: u64,
+    workers: usize,
+    // Blocks being compressed, which bounds the memory in use
+    pending: VecDeque<JoinHandle<std::io::Result<CompressedBlock>>>,
+}
+
+impl<'a, W> BlockWriter<'a, W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    // Writes the header of the archive
+    async fn new(
+        writer: &'a mut W,
+        header: &'a ArchiveHeader,
+        blocks: Option<&'a mpsc::Sender<Block>>,
+        workers: usize,
+    ) -> std::io::Result<Self> {
+        // The digest covers everything up to the end of the blocks, header included
+        let mut digest = Sha256::new();
+        let mut header_bytes = Vec::new();
+        header.write_to(&mut header_bytes).await?;
+        writer.write_all(&header_bytes).await?;
+        digest.update(&header_bytes);
+
+        Ok(Self {
+            writer,
+            header,
+            blocks,
+            digest,
+            offset: 0,
+            workers,
+            pending: VecDeque::new(),
+        })
+    }
+
+    async fn write_block(&mut self, data: Vec<u8>, compressible: bool) -> std::io::Result<()> {
+        let compression = self.header.compression;
+        if self.workers == 0 {
+            let block = CompressedBlock::new(data, compressible, compression)?;
+            return self.write_compressed(block).await;
+        }
+
+        while self.pending.len() >= self.workers {
+            self.write_next().await?;
+        }
+
+        self.pending.push_back(tokio::task::spawn_blocking(move || {
+            CompressedBlock::new(data, compressible, compression)
+        }));
+
+        Ok(())
+    }
+
+    // Writes the oldest block being compressed, once it is
+    async fn write_next(&mut self) -> std::io::Result<()> {
+        let Some(handle) = self.pending.pop_front() else {
+            return Ok(());
+        };
+        self.write_compressed(handle.await??).await
+    }
+
+    async fn write_compressed(&mut self, block: CompressedBlock) -> std::io::Result<()> {
+        let (flags, stored) = match &block.compressed {
+            Some(compressed) => (0, compressed.as_slice()),
+            None => (BLOCK_RAW, block.data.as_slice()),
+        };
+
+        let mut block_header = Vec::with_capacity(13);
+        block_header.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+        block_header.push(flags);
+        block_header.extend_from_slice(&block.checksum.to_le_bytes());
+
+        self.writer.write_all(&block_header).await?;
+        self.writer.write_all(stored).await?;
+        self.digest.update(&block_header);
+        self.digest.update(stored);
+        let offset = self.offset;
+        self.offset += (block_header.len() + stored.len()) as u64;
+
+        if let Some(blocks) = self.blocks {
+            let block = Block {
+                offset,
+                data: block.data,
+            };
+            let _ = blocks.send(block).await;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> std::io::Result<()> {
+        while !self.pending.is_empty() {
+            self.write_next().await?;
+        }
+
+        self.writer.write_u64_le(END_OF_BLOCKS).await?;
+        self.writer.write_all(&self.digest.finalize()).await
+    }
+}
+
+pub async fn compress_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    header: &ArchiveHeader,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    compress_stream_with_blocks(reader, writer, header, None, default_workers()).await
+}
+
+// Uncompressed blocks are also sent to `blocks`, if any, with offsets relative to the end of the header.
+// A closed channel does not stop the compression.
+// Up to `workers` blocks are compressed in parallel by blocking tasks, or inline if `workers` is 0.
+pub async fn compress_stream_with_blocks<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    header: &ArchiveHeader,
+    blocks: Option<&mpsc::Sender<Block>>,
+    workers: usize,
+) -> std::io::Result<()>
 where
     R: AsyncRead + Unpin + Send,
     W: AsyncWrite + Unpin + Send,
 {
-    let mut buffer = [0u8; BUFFER_SIZE];
+    let mut block_writer = BlockWriter::new(writer, header, blocks, workers).await?;
+    let block_size = header.block_size as usize;
 
     loop {
+        let mut buffer = vec![0u8; block_size];
         let bytes_read = reader.read(&mut buffer).await?;
         if bytes_read == 0 {
             break;
         }
 
-        let compressed = compress(&buffer[..bytes_read]);
+        buffer.truncate(bytes_read);
+        block_writer.write_block(buffer, true).await?;
+    }
+
+    block_writer.finish().await
+}
+
+// Compresses the chunks received from `chunks`, until the channel is closed.
+// Blocks never mix compressible and incompressible chunks.
+// Uncompressed blocks are also sent to `blocks`, as with `compress_stream_with_blocks`.
+pub async fn compress_chunks<W>(
+    mut chunks: mpsc::Receiver<Chunk>,
+    writer: &mut W,
+    header: &ArchiveHeader,
+    blocks: Option<&mpsc::Sender<Block>>,
+    workers: usize,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut block_writer = BlockWriter::new(writer, header, blocks, workers).await?;
+    let block_size = header.block_size as usize;
+    let mut buffer = Vec::with_capacity(block_size);
+    let mut compressible = true;
+
+    while let Some(chunk) = chunks.recv().await {
+        if chunk.compressible != compressible && !buffer.is_empty() {
+            let block = std::mem::replace(&mut buffer, Vec::with_capacity(block_size));
+            block_writer.write_block(block, compressible).await?;
+        }
+        compressible = chunk.compressible;
+
+        let mut data = chunk.data.as_slice();
+        while !data.is_empty() {
+            let len = data.len().min(block_size - buffer.len());
+            buffer.extend_from_slice(&data[..len]);
+            data = &data[len..];
 
-        writer.write_u64_le(compressed.len() as u64).await?;
-        writer.write_all(&compressed).await?;
+            if buffer.len() == block_size {
+                let block = std::mem::replace(&mut buffer, Vec::with_capacity(block_size));
+                block_writer.write_block(block, compressible).await?;
+            }
+        }
     }
 
-    Ok(())
+    if !buffer.is_empty() {
+        block_writer.write_block(buffer, compressible).await?;
+    }
+
+    block_writer.finish().await
 }
 
-pub async fn decompress_stream<R, W>(reader: &mut R, writer: &mut W) -> std::io::Result<()>
+// Decompresses the blocks of an archive, whose header has already been read.
+// The checksum of every block and the digest of the archive are verified, if the archive has them.
+// ## Errors
+// This function returns an error if the archive cannot be read, is truncated or is corrupted.
+pub async fn decompress_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    header: &ArchiveHeader,
+) -> std::io::Result<()>
 where
     R: AsyncRead + Unpin + Send,
     W: AsyncWrite + Unpin + Send,
 {
-    // If unlucky, compressed data can be slightly larger than the original data
-    // So we allocate a bigger buffer according to the worst case scenario (prepended size + header + data)
-    const MAX_UNCOMPRESSED_SIZE: usize = 4 + 258 + BUFFER_SIZE;
+    let digest = header_digest(header).await?;
+    decompress_blocks(reader, writer, header, Some(digest), 0).await
+}
 
-    let mut buffer = [0u8; MAX_UNCOMPRESSED_SIZE];
+// Decompresses the blocks of an archive from the block at `block_offset` in the compressed stream,
+// as the digest of the archive cannot be verified.
+// ## Errors
+// This function returns an error if the archive cannot be read, is truncated or is corrupted.
+pub async fn decompress_stream_from<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    header: &ArchiveHeader,
+    block_offset: u64,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    decompress_blocks(reader, writer, header, None, block_offset).await
+}
 
-    loop {
-        let result = reader.read_u64_le().await;
+/// Block of an archive whose content cannot be decompressed or does not match its checksum.
+#[derive(Debug)]
+pub struct CorruptedBlock {
+    /// Number of the block in the archive, starting from 0.
+    pub number: u64,
+    /// Offset of the block in the compressed stream.
+    pub offset: u64,
+    pub error: std::io::Error,
+}
+
+// Checks every block of an archive, whose header has already been read, and returns the corrupted ones.
+// The framing of a block does not depend on its content, so the blocks following a corrupted one are checked too.
+// ## Errors
+// This function returns an error if the archive cannot be read, is truncated, if the framing of a block is invalid,
+// or if the digest of the archive does not match while no block is corrupted.
+pub async fn scan_blocks<R>(
+    reader: &mut R,
+    header: &ArchiveHeader,
+) -> std::io::Result<Vec<CorruptedBlock>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let max_compressed_size = max_compressed_size(header);
+    let mut buffer = vec![0u8; max_compressed_size];
+    let mut digest = header_digest(header).await?;
+    let mut corrupted = Vec::new();
+    let mut number = 0;
+    let mut offset = 0;
+
+    while let Some(record) = BlockRecord::read_from(reader, header, max_compressed_size).await? {
+        reader.read_exact(&mut buffer[..record.size]).await?;
+        let data = &buffer[..record.size];
 
-        let size = usize::try_from(match result {
+        if let Err(error) = record.unpack(data, header) {
+            corrupted.push(CorruptedBlock {
+                number,
+                offset,
+                error,
+            });
+        }
+        record.update_digest(&mut digest, data);
+
+        number += 1;
+        offset += record.len(header);
+    }
+
+    // Corrupted blocks already explain a digest mismatch
+    if !header.is_legacy() {
+        match check_digest(reader, Some(digest)).await {
+            Err(e) if e.kind() == InvalidData && !corrupted.is_empty() => (),
+            result => result?,
+        }
+    }
+
+    Ok(corrupted)
+}
+
+// Starts the digest of an archive, which covers its header
+async fn header_digest(header: &ArchiveHeader) -> std::io::Result<Sha256> {
+    let mut digest = Sha256::new();
+    let mut header_bytes = Vec::new();
+    header.write_to(&mut header_bytes).await?;
+    digest.update(&header_bytes);
+    Ok(digest)
+}
+
+// If unlucky, compressed data can be slightly larger than the original data
+fn max_compressed_size(header: &ArchiveHeader) -> usize {
+    header
+        .compression
+        .codec
+        .max_compressed_size(header.block_size as usize)
+}
+
+// Framing of a block, preceding its stored content
+struct BlockRecord {
+    size: usize,
+    flags: u8,
+    checksum: Option<u32>,
+}
+
+impl BlockRecord {
+    // Reads the framing of the next block, or `None` after the last block
+    async fn read_from<R>(
+        reader: &mut R,
+        header: &ArchiveHeader,
+        max_compressed_size: usize,
+    ) -> std::io::Result<Option<Self>>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let size = match reader.read_u64_le().await {
+            Ok(END_OF_BLOCKS) if !header.is_legacy() => return Ok(None),
             Ok(x) => x,
-            // Unexpected EOF means all data has been read
-            Err(e) if e.kind() == UnexpectedEof => break,
+            Err(e) if e.kind() == UnexpectedEof && !header.is_legacy() => {
+                return Err(std::io::Error::new(UnexpectedEof, "Archive is truncated"));
+            }
+            // Legacy archives have no end marker, unexpected EOF means all data has been read
+            Err(e) if e.kind() == UnexpectedEof => return Ok(None),
             Err(e) => return Err(e),
-        })
-        .expect("Size is too big");
+        };
+        // Legacy blocks only hold LZ4 data, without flags nor checksum
+        let (flags, checksum) = if header.is_legacy() {
+            (0, None)
+        } else {
+            (reader.read_u8().await?, Some(reader.read_u32_le().await?))
+        };
+        let size = match usize::try_from(size) {
+            Ok(size) if size <= max_compressed_size => size,
+            _ => return Err(std::io::Error::new(InvalidData, "Block is too big")),
+        };
 
-        reader.read_exact(&mut buffer[..size]).await?;
+        Ok(Some(Self {
+            size,
+            flags,
+            checksum,
+        }))
+    }
 
-        let decompressed = decompress(&buffer[..size], MAX_UNCOMPRESSED_SIZE).map_err(|e| {
-            log::error!("Decompression failed: {}", e);
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Decompression failed")
+    // Length of the block in the compressed stream, framing included
+    const fn len(&self, header: &ArchiveHeader) -> u64 {
+        let framing = if header.is_legacy() { 8 } else { 13 };
+        framing + self.size as u64
+    }
+
+    // Returns the uncompressed content of the block, checking it against its checksum
+    fn unpack<'a>(&self, data: &'a [u8], header: &ArchiveHeader) -> std::io::Result<Cow<'a, [u8]>> {
+        let block_size = header.block_size as usize;
+        let decompressed = if self.flags & BLOCK_RAW == 0 {
+            Cow::Owned(decompress_block(
+                data,
+                header.compression.codec,
+                block_size,
+            )?)
+        } else if data.len() <= block_size {
+            Cow::Borrowed(data)
+        } else {
+            return Err(std::io::Error::new(InvalidData, "Block is too big"));
+        };
+
+        if let Some(checksum) = self.checksum {
+            if crc32fast::hash(&decompressed) != checksum {
+                return Err(std::io::Error::new(InvalidData, "Block checksum mismatch"));
+            }
+        }
+
+        Ok(decompressed)
+    }
+
+    fn update_digest(&self, digest: &mut Sha256, data: &[u8]) {
+        digest.update((self.size as u64).to_le_bytes());
+        digest.update([self.flags]);
+        if let Some(checksum) = self.checksum {
+            digest.update(checksum.to_le_bytes());
+        }
+        digest.update(data);
+    }
+}
+
+// Reads the digest following the end of the blocks, and compares it with `digest` if given
+async fn check_digest<R>(reader: &mut R, digest: Option<Sha256>) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut expected = [0u8; DIGEST_SIZE];
+    reader.read_exact(&mut expected).await.map_err(|e| {
+        if e.kind() == UnexpectedEof {
+            std::io::Error::new(UnexpectedEof, "Archive is truncated")
+        } else {
+            e
+        }
+    })?;
+    if let Some(digest) = digest {
+        if digest.finalize().as_slice() != expected {
+            return Err(std::io::Error::new(InvalidData, "Archive digest mismatch"));
+        }
+    }
+
+    Ok(())
+}
+
+async fn decompress_blocks<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    header: &ArchiveHeader,
+    mut digest: Option<Sha256>,
+    mut offset: u64,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let max_compressed_size = max_compressed_size(header);
+    let mut buffer = vec![0u8; max_compressed_size];
+    // Blocks can only be numbered when decompressing from the first one
+    let mut number = (offset == 0).then_some(0u64);
+
+    while let Some(record) = BlockRecord::read_from(reader, header, max_compressed_size).await? {
+        reader.read_exact(&mut buffer[..record.size]).await?;
+        let data = &buffer[..record.size];
+
+        let decompressed = record.unpack(data, header).map_err(|e| {
+            let block = number.map_or_else(
+                || format!("Block at offset {offset}"),
+                |number| format!("Block {number} at offset {offset}"),
+            );
+            std::io::Error::new(e.kind(), format!("{block}: {e}"))
         })?;
+        if let Some(digest) = &mut digest {
+            record.update_digest(digest, data);
+        }
+
         writer.write_all(&decompressed).await?;
+        number = number.map(|number| number + 1);
+        offset += record.len(header);
     }
 
-    Ok(())
+    if header.is_legacy() {
+        return Ok(());
+    }
+    check_digest(reader, digest).await
 }
@@ -1,27 +1,65 @@
 //! Forged Data General Security Engine (fDGSE)
 
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{consts::U12, rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
     Aes256Gcm, Key, Nonce,
 };
+use hkdf::Hkdf;
+use sha2::Sha256;
 use std::io::{
     Error,
     ErrorKind::{InvalidData, UnexpectedEof},
 };
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
 
-use crate::BUFFER_SIZE;
+use crate::fsas::SharedSecret;
+use crate::{offload_by_default, pipeline_depth, run_stage};
+use crate::{MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
 
 pub type CipherKey = Key<Aes256Gcm>;
 
 const NONCE_SIZE: usize = 12;
 const TAG_SIZE: usize = 16;
 
+/// Version of the protocol, checked and authenticated during the fSAS handshake.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Size of the identifier of a session.
+pub const SESSION_ID_SIZE: usize = 16;
+
+// Context of the key derived for a session
+const KEY_INFO: &[u8] = b"forgedbackup fDGSE session key";
+
+// Contexts of the values proving to the other peer that the key of a session is known
+const CLIENT_CONFIRMATION_INFO: &[u8] = b"forgedbackup fDGSE client key confirmation";
+const SERVER_CONFIRMATION_INFO: &[u8] = b"forgedbackup fDGSE server key confirmation";
+const CONFIRMATION_SIZE: usize = 32;
+
+// Context of the keys replacing the key of a session
+const REKEY_INFO: &[u8] = b"forgedbackup fDGSE rekey";
+
+/// Amount of plain text sent with a key before switching to the next one by default, in bytes.
+pub const DEFAULT_REKEY_AFTER: u64 = 1 << 36;
+
+// The frame is the last one of the stream
+const FRAME_LAST: u8 = 1 << 0;
+// The frame is the last one sent with the current key
+const FRAME_REKEY: u8 = 1 << 1;
+
 #[must_use]
 pub fn generate_key() -> CipherKey {
     Aes256Gcm::generate_key(&mut OsRng)
 }
 
+// Reads the pre-shared key at `key_path`, if there is one.
+#[must_use]
+pub fn read_optional_key(key_path: &str) -> Option<CipherKey> {
+    std::path::Path::new(key_path)
+        .exists()
+        .then(|| read_key(key_path))
+}
+
 #[must_use]
 pub fn read_key(key_path: &str) -> CipherKey {
     let key: &[u8; 32] = &std::fs::read(key_path)
@@ -31,81 +69,424 @@ pub fn read_key(key_path: &str) -> CipherKey {
     *Key::<Aes256Gcm>::from_slice(key)
 }
 
+fn random_session_id() -> [u8; SESSION_ID_SIZE] {
+    let mut id = [0u8; SESSION_ID_SIZE];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
+/// Parameters of an encrypted stream, chosen by the server for each connection.
+#[derive(Clone, Copy)]
+pub struct Session {
+    /// Random identifier, bound to every frame so that frames cannot be replayed in another session
+    pub id: [u8; SESSION_ID_SIZE],
+    /// Largest size of the plain text of a frame
+    pub block_size: u32,
+    /// Amount of plain text after which the sender switches to a new key, in bytes
+    pub rekey_after: u64,
+    /// Whether frames are encrypted and decrypted on blocking threads rather than in the task of the stream
+    pub offload: bool,
+    key: CipherKey,
+}
+
+impl Session {
+    #[must_use]
+    pub fn new(block_size: u32, key: CipherKey) -> Self {
+        Self {
+            id: random_session_id(),
+            block_size,
+            rekey_after: DEFAULT_REKEY_AFTER,
+            offload: offload_by_default(),
+            key,
+        }
+    }
+
+    // Derives the key of the session from the secret shared by the ephemeral keys of both peers,
+    // mixed with the pre-shared key if any, so that the static keys alone cannot decrypt it.
+    fn derive(
+        id: [u8; SESSION_ID_SIZE],
+        block_size: u32,
+        shared_secret: &SharedSecret,
+        psk: Option<&CipherKey>,
+    ) -> Self {
+        let mut ikm = shared_secret.as_bytes().to_vec();
+        if let Some(psk) = psk {
+            ikm.extend_from_slice(psk);
+        }
+
+        let mut key = CipherKey::default();
+        Hkdf::<Sha256>::new(Some(&id), &ikm)
+            .expand(KEY_INFO, &mut key)
+            .expect("Key length is valid for HKDF");
+
+        Self {
+            id,
+            block_size,
+            rekey_after: DEFAULT_REKEY_AFTER,
+            offload: offload_by_default(),
+            key,
+        }
+    }
+
+    // Value proving the knowledge of the key of the session, which also covers its block size
+    fn confirmation(&self, info: &[u8]) -> [u8; CONFIRMATION_SIZE] {
+        let mut confirmation = [0u8; CONFIRMATION_SIZE];
+        Hkdf::<Sha256>::from_prk(&self.key)
+            .expect("Key length is valid for HKDF")
+            .expand_multi_info(&[info, &self.block_size.to_le_bytes()], &mut confirmation)
+            .expect("Confirmation length is valid for HKDF");
+        confirmation
+    }
+}
+
+// Checks that either both peers or none of them use a pre-shared key
+fn check_psk_use(client_psk: bool, server_psk: bool) -> std::io::Result<()> {
+    if client_psk == server_psk {
+        return Ok(());
+    }
+
+    let (with, without) = if client_psk {
+        ("client", "server")
+    } else {
+        ("server", "client")
+    };
+    Err(Error::new(
+        InvalidData,
+        format!(
+            "Pre-shared key mismatch: the {with} uses a pre-shared key, the {without} does not"
+        ),
+    ))
+}
+
+// Checks the confirmation sent by the other peer, which differs when the peers derived different keys
+fn check_confirmation(
+    expected: &[u8; CONFIRMATION_SIZE],
+    received: &[u8; CONFIRMATION_SIZE],
+    psk: bool,
+) -> std::io::Result<()> {
+    if expected == received {
+        Ok(())
+    } else if psk {
+        Err(Error::new(
+            InvalidData,
+            "Pre-shared key mismatch: the client and the server use different pre-shared keys",
+        ))
+    } else {
+        Err(Error::new(
+            InvalidData,
+            "Key confirmation failed, the session was tampered with",
+        ))
+    }
+}
+
+// Key and position of one direction of a session, advanced frame by frame
+struct StreamKeys {
+    id: [u8; SESSION_ID_SIZE],
+    key: CipherKey,
+    cipher: Aes256Gcm,
+    seq: u64,
+}
+
+impl StreamKeys {
+    fn new(session: &Session) -> Self {
+        Self {
+            id: session.id,
+            key: session.key,
+            cipher: Aes256Gcm::new(&session.key),
+            seq: 0,
+        }
+    }
+
+    // Nonce and associated data of the next frame.
+    // Nonces are derived from the position of the frame, so they are never reused with a key,
+    // and the associated data binds the frame to the session and to its position in the stream.
+    fn next_frame(&mut self, flags: u8) -> (Nonce<U12>, [u8; SESSION_ID_SIZE + 9]) {
+        let mut nonce = Nonce::default();
+        nonce[NONCE_SIZE - 8..].copy_from_slice(&self.seq.to_be_bytes());
+
+        let mut aad = [0u8; SESSION_ID_SIZE + 9];
+        aad[..SESSION_ID_SIZE].copy_from_slice(&self.id);
+        aad[SESSION_ID_SIZE..SESSION_ID_SIZE + 8].copy_from_slice(&self.seq.to_le_bytes());
+        aad[SESSION_ID_SIZE + 8] = flags;
+
+        self.seq += 1;
+        (nonce, aad)
+    }
+
+    // Switches to the next key of the session, which cannot be used to recover the previous ones
+    fn rekey(&mut self) {
+        let mut key = CipherKey::default();
+        Hkdf::<Sha256>::from_prk(&self.key)
+            .expect("Key length is valid for HKDF")
+            .expand(REKEY_INFO, &mut key)
+            .expect("Key length is valid for HKDF");
+        self.key = key;
+        self.cipher = Aes256Gcm::new(&self.key);
+        log::trace!("Switched to the next key after frame {}", self.seq - 1);
+    }
+
+    fn encrypt(
+        &mut self,
+        flags: u8,
+        plain_text: &[u8],
+        frames: &mut Vec<u8>,
+    ) -> std::io::Result<()> {
+        let (nonce, aad) = self.next_frame(flags);
+        let payload = Payload {
+            msg: plain_text,
+            aad: &aad,
+        };
+        let cipher_text = self.cipher.encrypt(&nonce, payload).map_err(|e| {
+            log::error!("Encryption failed: {}", e);
+            Error::new(InvalidData, "Encryption failed")
+        })?;
+
+        frames.push(flags);
+        frames.extend_from_slice(&(cipher_text.len() as u64).to_le_bytes());
+        frames.extend_from_slice(&cipher_text);
+        Ok(())
+    }
+
+    fn decrypt(&mut self, flags: u8, cipher_text: &[u8]) -> std::io::Result<Vec<u8>> {
+        let seq = self.seq;
+        let (nonce, aad) = self.next_frame(flags);
+        let payload = Payload {
+            msg: cipher_text,
+            aad: &aad,
+        };
+        self.cipher.decrypt(&nonce, payload).map_err(|e| {
+            log::error!("Decryption failed for frame {}: {}", seq, e);
+            Error::new(
+                InvalidData,
+                "Decryption failed, frame is invalid, reordered or replayed",
+            )
+        })
+    }
+}
+
+// Sends the largest block size supported by the client and whether it uses a pre-shared key,
+// and returns the session chosen by the server, keyed from `shared_secret` and `psk`.
+// Both peers then prove that they derived the same key.
+// ## Errors
+// This function returns an error if the stream cannot be used, if the server chose an unsupported block size,
+// or if the peers do not use the same pre-shared key.
+pub async fn propose_session<S>(
+    stream: &mut S,
+    shared_secret: &SharedSecret,
+    psk: Option<&CipherKey>,
+) -> std::io::Result<Session>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    stream.write_u32_le(MAX_BLOCK_SIZE).await?;
+    stream.write_u8(u8::from(psk.is_some())).await?;
+
+    let block_size = stream.read_u32_le().await?;
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err(Error::new(
+            InvalidData,
+            format!("Unsupported block size: {block_size}"),
+        ));
+    }
+
+    let mut id = [0u8; SESSION_ID_SIZE];
+    stream.read_exact(&mut id).await?;
+    let server_psk = stream.read_u8().await? != 0;
+    check_psk_use(psk.is_some(), server_psk)?;
+
+    let session = Session::derive(id, block_size, shared_secret, psk);
+    stream
+        .write_all(&session.confirmation(CLIENT_CONFIRMATION_INFO))
+        .await?;
+
+    let mut confirmation = [0u8; CONFIRMATION_SIZE];
+    stream.read_exact(&mut confirmation).await?;
+    check_confirmation(
+        &session.confirmation(SERVER_CONFIRMATION_INFO),
+        &confirmation,
+        psk.is_some(),
+    )?;
+
+    Ok(session)
+}
+
+// Receives the proposal of the client, and answers with a new session,
+// whose block size is `block_size` unless the client does not support it,
+// keyed from `shared_secret` and `psk`.
+// Both peers then prove that they derived the same key.
+// ## Errors
+// This function returns an error if the stream cannot be used, if the client does not support the minimum block size,
+// or if the peers do not use the same pre-shared key.
+pub async fn answer_session<S>(
+    stream: &mut S,
+    block_size: u32,
+    shared_secret: &SharedSecret,
+    psk: Option<&CipherKey>,
+) -> std::io::Result<Session>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let block_size = block_size.min(stream.read_u32_le().await?);
+    let client_psk = stream.read_u8().await? != 0;
+    if block_size < MIN_BLOCK_SIZE {
+        return Err(Error::new(
+            InvalidData,
+            format!("Unsupported block size: {block_size}"),
+        ));
+    }
+
+    let session = Session::derive(random_session_id(), block_size, shared_secret, psk);
+    stream.write_u32_le(session.block_size).await?;
+    stream.write_all(&session.id).await?;
+    // The client is told whether a pre-shared key is used before any mismatch is reported
+    stream.write_u8(u8::from(psk.is_some())).await?;
+    check_psk_use(client_psk, psk.is_some())?;
+
+    // The server proves its key even if the client failed to, so that both peers report the mismatch
+    let mut confirmation = [0u8; CONFIRMATION_SIZE];
+    stream.read_exact(&mut confirmation).await?;
+    let checked = check_confirmation(
+        &session.confirmation(CLIENT_CONFIRMATION_INFO),
+        &confirmation,
+        psk.is_some(),
+    );
+    stream
+        .write_all(&session.confirmation(SERVER_CONFIRMATION_INFO))
+        .await?;
+    checked?;
+
+    Ok(session)
+}
+
+// A frame of the stream, as read by `decipher_stream`
+struct Frame {
+    flags: u8,
+    cipher_text: Vec<u8>,
+}
+
+// Ciphers the stream, by frames of at most `session.block_size` bytes of plain text,
+// each bound to the session and to its position in the stream.
+// Once `session.rekey_after` bytes were sent with a key, a frame announces the switch to the next key.
+// The stream ends with an empty frame marked as the last one, so that truncation can be detected.
+// With `session.offload`, encryption runs in a blocking stage, so that concurrent streams do not stall the executor.
+// ## Errors
+// This function returns an error if the stream cannot be read or written.
 pub async fn cipher_stream<R, W>(
     reader: &mut R,
     writer: &mut W,
-    key: &CipherKey,
+    session: &Session,
 ) -> std::io::Result<()>
 where
     R: AsyncRead + Unpin + Send,
     W: AsyncWrite + Unpin + Send,
 {
-    let mut buffer = [0u8; BUFFER_SIZE];
-    let cipher = Aes256Gcm::new(key);
+    let block_size = session.block_size as usize;
+    let rekey_after = session.rekey_after;
+    let depth = pipeline_depth(block_size);
+    let (chunks_tx, chunks_rx) = mpsc::channel::<(Vec<u8>, u8)>(depth);
 
-    loop {
-        let bytes_read = reader.read(&mut buffer).await?;
-        if bytes_read == 0 {
-            break;
+    let mut keys = StreamKeys::new(session);
+    let mut bytes_since_rekey = 0u64;
+    let encrypt = move |(chunk, flags): (Vec<u8>, u8), frames: &mut Vec<u8>| {
+        let chunk_size = chunk.len() as u64;
+        if bytes_since_rekey > 0 && bytes_since_rekey + chunk_size > rekey_after {
+            // The switch is authenticated with the current key, so it cannot be forged or skipped
+            keys.encrypt(FRAME_REKEY, &[], frames)?;
+            keys.rekey();
+            bytes_since_rekey = 0;
         }
+        bytes_since_rekey += chunk_size;
 
-        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        writer.write_all(&nonce).await?;
+        keys.encrypt(flags, &chunk, frames)
+    };
 
-        let cipher_text = cipher.encrypt(&nonce, &buffer[..bytes_read]).map_err(|e| {
-            log::error!("Encryption failed: {}", e);
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Encryption failed")
-        })?;
+    let read = async move {
+        loop {
+            // Full frames keep their number, and the work of handing them to the stage, low
+            let mut chunk = vec![0u8; block_size];
+            let mut bytes_read = 0;
+            while bytes_read < block_size {
+                match reader.read(&mut chunk[bytes_read..]).await? {
+                    0 => break,
+                    n => bytes_read += n,
+                }
+            }
+            chunk.truncate(bytes_read);
+            let flags = if bytes_read == 0 { FRAME_LAST } else { 0 };
 
-        writer.write_u64_le(cipher_text.len() as u64).await?;
+            // The stage only stops on errors, which are reported by the writing side
+            if chunks_tx.send((chunk, flags)).await.is_err() || flags & FRAME_LAST != 0 {
+                break;
+            }
+        }
+        Ok(())
+    };
 
-        writer.write_all(&cipher_text).await?;
-    }
+    tokio::try_join!(read, run_stage(chunks_rx, writer, session.offload, encrypt))?;
 
     Ok(())
 }
 
+// Deciphers the stream written by `cipher_stream` with the same session.
+// With `session.offload`, decryption runs in a blocking stage, as with `cipher_stream`.
+// ## Errors
+// This function returns an error if the stream cannot be read or written, if a frame is invalid,
+// if the stream is truncated, or if frames were reordered or replayed.
 pub async fn decipher_stream<R, W>(
     reader: &mut R,
     writer: &mut W,
-    key: CipherKey,
+    session: &Session,
 ) -> std::io::Result<()>
 where
     R: AsyncRead + Unpin + Send,
     W: AsyncWrite + Unpin + Send,
 {
-    let mut buffer = [0u8; BUFFER_SIZE + TAG_SIZE];
-    let mut nonce = [0u8; NONCE_SIZE];
-    let cipher = Aes256Gcm::new(&key);
-
-    loop {
-        let nonce = {
-            let result = reader.read_exact(&mut nonce).await;
-            match result {
-                Ok(_) => (),
-                // Unexpected EOF means all data has been read
-                Err(e) if e.kind() == UnexpectedEof => break,
-                Err(e) => return Err(e),
-            }
-            nonce
-        };
+    let block_size = session.block_size as usize;
+    let depth = pipeline_depth(block_size);
+    let (frames_tx, frames_rx) = mpsc::channel::<Frame>(depth);
 
-        let size = usize::try_from(reader.read_u64_le().await?).expect("Size is too big");
-        if size == 0 {
-            break;
+    let mut keys = StreamKeys::new(session);
+    let decrypt = move |frame: Frame, chunks: &mut Vec<u8>| {
+        let plain_text = keys.decrypt(frame.flags, &frame.cipher_text)?;
+        if frame.flags & FRAME_REKEY != 0 {
+            keys.rekey();
         }
 
-        let _ = reader.read_exact(&mut buffer[..size]).await?;
+        chunks.extend_from_slice(&plain_text);
+        Ok(())
+    };
 
-        let nonce = Nonce::from_slice(&nonce);
+    let read = async move {
+        loop {
+            let flags = match reader.read_u8().await {
+                Ok(flags) if flags & !(FRAME_LAST | FRAME_REKEY) == 0 => flags,
+                Ok(_) => return Err(Error::new(InvalidData, "Invalid frame flags")),
+                // The last frame is missing
+                Err(e) if e.kind() == UnexpectedEof => {
+                    return Err(Error::new(UnexpectedEof, "Stream is truncated"));
+                }
+                Err(e) => return Err(e),
+            };
 
-        let plain_text = cipher.decrypt(nonce, &buffer[..size]).map_err(|e| {
-            log::error!("Decryption failed: {}", e);
-            Error::new(InvalidData, "Decryption failed")
-        })?;
+            let size = match usize::try_from(reader.read_u64_le().await?) {
+                Ok(size) if (TAG_SIZE..=block_size + TAG_SIZE).contains(&size) => size,
+                _ => return Err(Error::new(InvalidData, "Invalid frame size")),
+            };
 
-        writer.write_all(&plain_text).await?;
-    }
+            let mut cipher_text = vec![0u8; size];
+            reader.read_exact(&mut cipher_text).await?;
+
+            // The flags are only trusted once the stage has authenticated them
+            let frame = Frame { flags, cipher_text };
+            // The stage only stops on errors, which are reported by the writing side
+            if frames_tx.send(frame).await.is_err() || flags & FRAME_LAST != 0 {
+                break;
+            }
+        }
+        Ok(())
+    };
+
+    tokio::try_join!(read, run_stage(frames_rx, writer, session.offload, decrypt))?;
 
     Ok(())
 }
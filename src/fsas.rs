@@ -4,10 +4,48 @@ use ed25519_dalek::{Signature, Signer, Verifier};
 pub use ed25519_dalek::{SigningKey, VerifyingKey};
 use ed25519_dalek::{PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH, SIGNATURE_LENGTH};
 use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
 use std::{fs, io};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+pub use x25519_dalek::SharedSecret;
+use x25519_dalek::{EphemeralSecret, PublicKey as EphemeralPublicKey};
+
+use crate::fdgse::PROTOCOL_VERSION;
 
 const CHALLENGE_LENGTH: usize = 512;
+const EPHEMERAL_KEY_LENGTH: usize = 32;
+
+// Domain separation of the hashes and signatures of the handshake
+const TRANSCRIPT_LABEL: &[u8] = b"forgedbackup fSAS transcript";
+const CLIENT_LABEL: &[u8] = b"forgedbackup fSAS client signature";
+const SERVER_LABEL: &[u8] = b"forgedbackup fSAS server signature";
+
+// Key pair generated for a single connection, for forward secrecy
+struct Ephemeral {
+    secret: EphemeralSecret,
+    public: EphemeralPublicKey,
+}
+
+impl Ephemeral {
+    fn generate() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng {});
+        let public = EphemeralPublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    // Computes the secret shared with the peer owning `peer_public`.
+    // ## Errors
+    // This function returns an error if the key of the peer is a low order point,
+    // which would make the shared secret predictable.
+    fn agree(self, peer_public: &EphemeralPublicKey) -> io::Result<SharedSecret> {
+        let shared_secret = self.secret.diffie_hellman(peer_public);
+        if !shared_secret.was_contributory() {
+            return Err(io::Error::other("Invalid ephemeral key"));
+        }
+        Ok(shared_secret)
+    }
+}
 
 #[derive(Clone)]
 pub struct KeyPair {
@@ -50,7 +88,7 @@ pub fn read_verifying_key(bytes_file: &str) -> Result<VerifyingKey, io::Error> {
     );
     let verifying_key: [u8; PUBLIC_KEY_LENGTH] = verifying_key.try_into().unwrap();
     VerifyingKey::from_bytes(&verifying_key)
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to import verifying key"))
+        .map_err(|_| io::Error::other("Failed to import verifying key"))
 }
 
 fn verify_signature(
@@ -60,38 +98,159 @@ fn verify_signature(
 ) -> io::Result<()> {
     verifying_key
         .verify(message, signature)
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to authenticate the client"))
+        .map_err(|_| io::Error::other("Failed to authenticate the peer"))
 }
 
-pub async fn send_and_verify_challenge(
-    stream: &mut tokio::net::TcpStream,
-    verifying_key: &VerifyingKey,
+// Running hash of everything both peers agreed on during the handshake
+struct Transcript(Sha256);
+
+impl Transcript {
+    fn new(client_hostname: &str, server_hostname: &str) -> Self {
+        let mut transcript = Self(Sha256::new());
+        transcript.update(TRANSCRIPT_LABEL);
+        transcript.update(&PROTOCOL_VERSION.to_le_bytes());
+        transcript.update_with_length(client_hostname.as_bytes());
+        transcript.update_with_length(server_hostname.as_bytes());
+        transcript
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    // Variable length fields are prefixed with their length, so that they cannot be shifted
+    fn update_with_length(&mut self, bytes: &[u8]) {
+        let length = u16::try_from(bytes.len()).expect("Hostname is too long");
+        self.update(&length.to_le_bytes());
+        self.update(bytes);
+    }
+
+    // The label makes the signature of a peer unusable in the other role
+    fn signed_message(&self, role_label: &[u8]) -> Vec<u8> {
+        let mut message = role_label.to_vec();
+        message.extend_from_slice(&self.0.clone().finalize());
+        message
+    }
+}
+
+async fn read_challenge_and_key(
+    stream: &mut TcpStream,
+    transcript: &mut Transcript,
+) -> io::Result<EphemeralPublicKey> {
+    let mut challenge = [0u8; CHALLENGE_LENGTH];
+    let mut ephemeral_public = [0u8; EPHEMERAL_KEY_LENGTH];
+    stream.read_exact(&mut challenge).await?;
+    stream.read_exact(&mut ephemeral_public).await?;
+
+    transcript.update(&challenge);
+    transcript.update(&ephemeral_public);
+
+    Ok(EphemeralPublicKey::from(ephemeral_public))
+}
+
+async fn write_challenge_and_key(
+    stream: &mut TcpStream,
+    transcript: &mut Transcript,
+    ephemeral_public: &EphemeralPublicKey,
 ) -> io::Result<()> {
     let mut challenge = [0u8; CHALLENGE_LENGTH];
     OsRng {}.fill_bytes(&mut challenge[..]);
-    let mut signature = [0u8; SIGNATURE_LENGTH];
 
     stream.write_all(&challenge).await?;
+    stream.write_all(ephemeral_public.as_bytes()).await?;
+
+    transcript.update(&challenge);
+    transcript.update(ephemeral_public.as_bytes());
+
+    Ok(())
+}
 
+async fn read_signature(stream: &mut TcpStream) -> io::Result<Signature> {
+    let mut signature = [0u8; SIGNATURE_LENGTH];
     stream.read_exact(&mut signature).await?;
+    Ok(Signature::from_bytes(&signature))
+}
 
+// Authenticates the server named `server_hostname` on behalf of the client `client_hostname`,
+// and returns the secret shared with it.
+// Both peers sign the transcript of the handshake, so that it cannot be relayed or altered.
+// ## Errors
+// This function returns an error if the stream cannot be used, if the server speaks another protocol version,
+// if it does not have the expected hostname, or if it could not be authenticated.
+pub async fn authenticate_server(
+    stream: &mut TcpStream,
+    client_hostname: &str,
+    server_hostname: &str,
+    keypair: &KeyPair,
+) -> io::Result<SharedSecret> {
+    let version = stream.read_u16_le().await?;
+    if version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported protocol version {version}, expected {PROTOCOL_VERSION}"),
+        ));
+    }
+
+    let mut hostname = vec![0u8; usize::from(stream.read_u16_le().await?)];
+    stream.read_exact(&mut hostname).await?;
+    if hostname != server_hostname.as_bytes() {
+        return Err(io::Error::other(format!(
+            "Unexpected server hostname: {}",
+            String::from_utf8_lossy(&hostname)
+        )));
+    }
+
+    let mut transcript = Transcript::new(client_hostname, server_hostname);
+    let ephemeral = Ephemeral::generate();
+    let server_public = read_challenge_and_key(stream, &mut transcript).await?;
+    write_challenge_and_key(stream, &mut transcript, &ephemeral.public).await?;
+
+    let signature = keypair
+        .signing_key
+        .sign(&transcript.signed_message(CLIENT_LABEL));
+    stream.write_all(&signature.to_bytes()).await?;
+
+    let signature = read_signature(stream).await?;
     verify_signature(
-        verifying_key,
-        &Signature::from_bytes(&signature),
-        &challenge,
-    )
+        &keypair.verifying_key,
+        &signature,
+        &transcript.signed_message(SERVER_LABEL),
+    )?;
+
+    ephemeral.agree(&server_public)
 }
 
-pub async fn receive_and_answer_challenge(
-    stream: &mut tokio::net::TcpStream,
-    signing_key: &SigningKey,
-) -> Result<(), std::io::Error> {
-    let mut challenge = [0u8; CHALLENGE_LENGTH];
-    stream.read_exact(&mut challenge).await?;
+// Authenticates the client named `client_hostname` to the server `server_hostname`,
+// and returns the secret shared with it.
+// ## Errors
+// This function returns an error if the stream cannot be used, or if the client could not be authenticated.
+pub async fn authenticate_client(
+    stream: &mut TcpStream,
+    client_hostname: &str,
+    server_hostname: &str,
+    keypair: &KeyPair,
+) -> io::Result<SharedSecret> {
+    let hostname_length = u16::try_from(server_hostname.len()).expect("Hostname is too long");
+    stream.write_u16_le(PROTOCOL_VERSION).await?;
+    stream.write_u16_le(hostname_length).await?;
+    stream.write_all(server_hostname.as_bytes()).await?;
 
-    let signature = signing_key.sign(&challenge);
+    let mut transcript = Transcript::new(client_hostname, server_hostname);
+    let ephemeral = Ephemeral::generate();
+    write_challenge_and_key(stream, &mut transcript, &ephemeral.public).await?;
+    let client_public = read_challenge_and_key(stream, &mut transcript).await?;
 
+    let signature = read_signature(stream).await?;
+    verify_signature(
+        &keypair.verifying_key,
+        &signature,
+        &transcript.signed_message(CLIENT_LABEL),
+    )?;
+
+    let signature = keypair
+        .signing_key
+        .sign(&transcript.signed_message(SERVER_LABEL));
     stream.write_all(&signature.to_bytes()).await?;
 
-    Ok(())
+    ephemeral.agree(&client_public)
 }
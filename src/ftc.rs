@@ -0,0 +1,476 @@
+//! Forged Tar Converter (fTC)
+
+use std::io::Read;
+use std::path::{Component, PathBuf};
+
+use tar::{EntryType, Header};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::fadc::{
+    self, CrawlError, CrawlSummary, Entry, EntryKind, EntryMetadata, Record, Timestamp, TRAILER_TAG,
+};
+use crate::BUFFER_SIZE;
+
+const BLOCK_SIZE: usize = 512;
+// Name of pax extended headers, as used by most implementations
+const PAX_HEADER_NAME: &[u8] = b"././@PaxHeader";
+
+// Largest values that fit in the octal fields of ustar headers
+const MAX_USTAR_ID: u32 = 0o7_777_777;
+const MAX_USTAR_SIZE: u64 = 0o77_777_777_777;
+
+const fn entry_kind(entry_type: EntryType) -> Option<EntryKind> {
+    match entry_type {
+        EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => Some(EntryKind::File),
+        EntryType::Directory => Some(EntryKind::Directory),
+        EntryType::Symlink => Some(EntryKind::Symlink),
+        EntryType::Link => Some(EntryKind::Hardlink),
+        EntryType::Fifo => Some(EntryKind::Fifo),
+        EntryType::Char => Some(EntryKind::CharDevice),
+        EntryType::Block => Some(EntryKind::BlockDevice),
+        _ => None,
+    }
+}
+
+const fn entry_type(kind: EntryKind) -> EntryType {
+    match kind {
+        EntryKind::File => EntryType::Regular,
+        EntryKind::Directory => EntryType::Directory,
+        EntryKind::Symlink => EntryType::Symlink,
+        EntryKind::Hardlink => EntryType::Link,
+        EntryKind::Fifo => EntryType::Fifo,
+        EntryKind::CharDevice => EntryType::Char,
+        EntryKind::BlockDevice => EntryType::Block,
+    }
+}
+
+// Same encoding as the Linux `major` and `minor` macros
+fn device_numbers(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0x0000_0fff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0x0000_00ff);
+    (
+        u32::try_from(major).unwrap_or(u32::MAX),
+        u32::try_from(minor).unwrap_or(u32::MAX),
+    )
+}
+
+// Inverse of `device_numbers`, same as the Linux `makedev` macro
+fn device_number(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (u64::from(major), u64::from(minor));
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0x0000_0fff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0x0000_00ff)
+}
+
+// Pax records are `<length> <key>=<value>\n`, the length including its own digits
+fn push_pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let len = key.len() + value.len() + 3;
+    let mut total = len + len.to_string().len();
+    while total != len + total.to_string().len() {
+        total = len + total.to_string().len();
+    }
+
+    records.extend_from_slice(format!("{total} {key}=").as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+fn pax_time(time: Timestamp) -> Vec<u8> {
+    if time.secs < 0 && time.nanos > 0 {
+        format!("-{}.{:09}", -(time.secs + 1), 1_000_000_000 - time.nanos).into_bytes()
+    } else {
+        format!("{}.{:09}", time.secs, time.nanos).into_bytes()
+    }
+}
+
+// Parses pax times, e.g. `1700000000.5` or `-1.25`
+fn parse_pax_time(value: &str) -> Option<Timestamp> {
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let mut time = Timestamp {
+        secs: secs.parse().ok()?,
+        nanos: 0,
+    };
+
+    let digits = fraction.get(..fraction.len().min(9))?;
+    if !digits.is_empty() {
+        let nanos: u32 = digits.parse().ok()?;
+        time.nanos = nanos * 10u32.pow(9 - u32::try_from(digits.len()).ok()?);
+    }
+
+    if secs.starts_with('-') && time.nanos > 0 {
+        time.secs -= 1;
+        time.nanos = 1_000_000_000 - time.nanos;
+    }
+
+    Some(time)
+}
+
+// Copies the start of `bytes` in a header field, for readers ignoring pax headers
+fn copy_truncated(field: &mut [u8], bytes: &[u8]) {
+    let len = bytes.len().min(field.len());
+    field.fill(0);
+    field[..len].copy_from_slice(&bytes[..len]);
+}
+
+// Returns the ustar header of an entry, along with the pax records for what does not fit in it
+fn headers(entry: &Entry) -> std::io::Result<(Header, Vec<u8>)> {
+    let mut header = Header::new_ustar();
+    let mut records = Vec::new();
+
+    header.set_entry_type(entry_type(entry.kind));
+    header.set_mode(entry.metadata.mode & 0o7777);
+
+    header.set_uid(u64::from(entry.metadata.uid.min(MAX_USTAR_ID)));
+    if entry.metadata.uid > MAX_USTAR_ID {
+        push_pax_record(
+            &mut records,
+            "uid",
+            entry.metadata.uid.to_string().as_bytes(),
+        );
+    }
+    header.set_gid(u64::from(entry.metadata.gid.min(MAX_USTAR_ID)));
+    if entry.metadata.gid > MAX_USTAR_ID {
+        push_pax_record(
+            &mut records,
+            "gid",
+            entry.metadata.gid.to_string().as_bytes(),
+        );
+    }
+
+    let mtime = entry.metadata.mtime;
+    header.set_mtime(u64::try_from(mtime.secs).unwrap_or(0));
+    if mtime.secs < 0 || mtime.nanos != 0 {
+        push_pax_record(&mut records, "mtime", &pax_time(mtime));
+        push_pax_record(&mut records, "atime", &pax_time(entry.metadata.atime));
+    }
+
+    let size = if entry.kind == EntryKind::File {
+        entry.size
+    } else {
+        0
+    };
+    header.set_size(size.min(MAX_USTAR_SIZE));
+    if size > MAX_USTAR_SIZE {
+        push_pax_record(&mut records, "size", size.to_string().as_bytes());
+    }
+
+    let mut binary = false;
+
+    let path = entry.path.as_os_str().as_encoded_bytes();
+    if header.set_path(&entry.path).is_err() {
+        push_pax_record(&mut records, "path", path);
+        copy_truncated(&mut header.as_old_mut().name, path);
+        binary |= std::str::from_utf8(path).is_err();
+    }
+
+    if let Some(target) = &entry.target {
+        let target = target.as_os_str().as_encoded_bytes();
+        if header.set_link_name_literal(target).is_err() {
+            push_pax_record(&mut records, "linkpath", target);
+            copy_truncated(&mut header.as_old_mut().linkname, target);
+            binary |= std::str::from_utf8(target).is_err();
+        }
+    }
+
+    if binary {
+        push_pax_record(&mut records, "hdrcharset", b"BINARY");
+    }
+
+    if matches!(entry.kind, EntryKind::CharDevice | EntryKind::BlockDevice) {
+        let (major, minor) = device_numbers(entry.rdev);
+        header.set_device_major(major)?;
+        header.set_device_minor(minor)?;
+    }
+
+    header.set_cksum();
+
+    Ok((header, records))
+}
+
+// Completes the last block of data of `len` bytes with zeros
+async fn write_padding<W>(writer: &mut W, len: u64) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let padding = (BLOCK_SIZE as u64 - len % BLOCK_SIZE as u64) % BLOCK_SIZE as u64;
+    writer
+        .write_all(&[0u8; BLOCK_SIZE][..usize::try_from(padding).unwrap()])
+        .await
+}
+
+async fn write_pax_header<W>(writer: &mut W, entry: &Entry, records: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    copy_truncated(&mut header.as_old_mut().name, PAX_HEADER_NAME);
+    header.set_mode(0o644);
+    header.set_mtime(u64::try_from(entry.metadata.mtime.secs).unwrap_or(0));
+    header.set_size(records.len() as u64);
+    header.set_cksum();
+
+    writer.write_all(header.as_bytes()).await?;
+    writer.write_all(records).await?;
+    write_padding(writer, records.len() as u64).await
+}
+
+// Converts the stream into a pax tar archive.
+// Returns the trailer of the stream, if any.
+// ## Errors
+// This function returns an error if the stream is invalid or the archive cannot be written.
+pub async fn export_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    max_path_len: usize,
+) -> std::io::Result<Option<CrawlSummary>>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut summary = None;
+
+    while let Some(record) = fadc::read_record(reader, max_path_len).await? {
+        let entry = match record {
+            Record::Entry(entry) => entry,
+            Record::Trailer(trailer) => {
+                summary = Some(trailer);
+                break;
+            }
+        };
+
+        let (header, records) = headers(&entry)?;
+        if !records.is_empty() {
+            write_pax_header(writer, &entry, &records).await?;
+        }
+        writer.write_all(header.as_bytes()).await?;
+
+        if entry.kind == EntryKind::File {
+            if fadc::read_content(reader, &entry, writer).await? {
+                log::warn!(
+                    "File changed during backup, its content may be inconsistent: {:?}",
+                    entry.path
+                );
+            }
+            write_padding(writer, entry.size).await?;
+        }
+    }
+
+    // End of archive
+    writer.write_all(&[0u8; 2 * BLOCK_SIZE]).await?;
+    writer.flush().await?;
+
+    Ok(summary)
+}
+
+// Paths of tar archives are arbitrary bytes, which can only be kept as is on Unix
+#[cfg(unix)]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).as_ref())
+}
+
+// Makes the path of a tar entry relative, as in the stream.
+// Returns `None` for the root of the archive and for paths escaping it.
+fn normalize_path(bytes: &[u8]) -> Option<PathBuf> {
+    let path = bytes_to_path(bytes);
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    (normalized.components().next().is_some()).then_some(normalized)
+}
+
+enum Message {
+    Entry(Entry),
+    Content(Vec<u8>),
+}
+
+// Converts a tar entry, returning `None` for entries which are not part of the stream
+fn convert_entry<R: Read>(
+    tar_entry: &mut tar::Entry<'_, R>,
+) -> std::io::Result<Option<Result<Entry, CrawlError>>> {
+    let header = tar_entry.header().clone();
+    let path_bytes = tar_entry.path_bytes().into_owned();
+    let path = String::from_utf8_lossy(&path_bytes).into_owned();
+
+    if header.entry_type() == EntryType::XGlobalHeader {
+        return Ok(None);
+    }
+    let error = |message: &str| {
+        Ok(Some(Err(CrawlError {
+            path: PathBuf::from(&path),
+            message: message.to_string(),
+        })))
+    };
+
+    let Some(kind) = entry_kind(header.entry_type()) else {
+        return error("Unsupported tar entry type");
+    };
+    let Some(entry_path) = normalize_path(&path_bytes) else {
+        // The root of the archive is the output directory itself
+        return if path.trim_matches(|c| c == '.' || c == '/').is_empty() {
+            Ok(None)
+        } else {
+            error("Path escapes the archive")
+        };
+    };
+
+    // GNU archives store times before the Unix epoch in two's complement
+    let mtime = Timestamp {
+        secs: i64::from_ne_bytes(header.mtime()?.to_ne_bytes()),
+        nanos: 0,
+    };
+    let mut entry = Entry {
+        kind,
+        path: entry_path,
+        metadata: EntryMetadata {
+            mode: header.mode()? & 0o7777,
+            uid: u32::try_from(header.uid()?).unwrap_or(u32::MAX),
+            gid: u32::try_from(header.gid()?).unwrap_or(u32::MAX),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+        },
+        size: 0,
+        target: None,
+        rdev: 0,
+    };
+
+    if let Some(extensions) = tar_entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let (Ok(key), Ok(value)) = (extension.key(), extension.value()) else {
+                continue;
+            };
+            let metadata = &mut entry.metadata;
+            match key {
+                "uid" => metadata.uid = value.parse().unwrap_or(metadata.uid),
+                "gid" => metadata.gid = value.parse().unwrap_or(metadata.gid),
+                "mtime" => metadata.mtime = parse_pax_time(value).unwrap_or(metadata.mtime),
+                "atime" => metadata.atime = parse_pax_time(value).unwrap_or(metadata.atime),
+                "ctime" => metadata.ctime = parse_pax_time(value).unwrap_or(metadata.ctime),
+                _ => {}
+            }
+        }
+    }
+
+    match kind {
+        EntryKind::File => entry.size = tar_entry.size(),
+        EntryKind::Symlink => {
+            let target = tar_entry.link_name_bytes().unwrap_or_default();
+            entry.target = Some(bytes_to_path(&target));
+        }
+        EntryKind::Hardlink => {
+            let target = tar_entry.link_name_bytes().unwrap_or_default();
+            let Some(target) = normalize_path(&target) else {
+                return error("Hardlink target escapes the archive");
+            };
+            entry.target = Some(target);
+        }
+        EntryKind::CharDevice | EntryKind::BlockDevice => {
+            let major = header.device_major()?.unwrap_or(0);
+            let minor = header.device_minor()?.unwrap_or(0);
+            entry.rdev = device_number(major, minor);
+        }
+        EntryKind::Directory | EntryKind::Fifo => {}
+    }
+
+    Ok(Some(Ok(entry)))
+}
+
+// Reads the tar archive, sending its entries along with the content of files
+fn read_archive<R: Read>(reader: R, tx: &mpsc::Sender<Message>) -> std::io::Result<CrawlSummary> {
+    let closed = || std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+    let mut summary = CrawlSummary::default();
+    let mut archive = tar::Archive::new(reader);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+
+    for tar_entry in archive.entries()? {
+        let mut tar_entry = tar_entry?;
+
+        let entry = match convert_entry(&mut tar_entry)? {
+            Some(Ok(entry)) => entry,
+            Some(Err(error)) => {
+                log::warn!("Skipping {:?}: {}", error.path, error.message);
+                summary.errors.push(error);
+                continue;
+            }
+            None => continue,
+        };
+        let kind = entry.kind;
+        tx.blocking_send(Message::Entry(entry))
+            .map_err(|_| closed())?;
+
+        if kind == EntryKind::File {
+            loop {
+                let bytes_read = tar_entry.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                tx.blocking_send(Message::Content(buffer[..bytes_read].to_vec()))
+                    .map_err(|_| closed())?;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+// Converts a tar archive into the stream, as sent by the crawler.
+// Entries that cannot be converted are recorded in the trailer of the stream.
+// ## Errors
+// This function returns an error if the archive is invalid or the stream cannot be written.
+pub async fn import_stream<R, W>(reader: R, writer: &mut W) -> std::io::Result<CrawlSummary>
+where
+    R: Read + Send + 'static,
+    W: AsyncWrite + Unpin + Send,
+{
+    let (tx, mut rx) = mpsc::channel(16);
+    let read_handle = tokio::task::spawn_blocking(move || read_archive(reader, &tx));
+
+    // Content of the current file left to write
+    let mut remaining = 0;
+    while let Some(message) = rx.recv().await {
+        match message {
+            Message::Entry(entry) => {
+                entry.write_to(writer).await?;
+                if entry.kind == EntryKind::File {
+                    remaining = entry.size;
+                    if remaining == 0 {
+                        // The content of files from an archive is never inconsistent
+                        writer.write_u8(0).await?;
+                    }
+                }
+            }
+            Message::Content(data) => {
+                writer.write_all(&data).await?;
+                remaining -= data.len() as u64;
+                if remaining == 0 {
+                    writer.write_u8(0).await?;
+                }
+            }
+        }
+    }
+
+    let summary = read_handle.await??;
+
+    writer.write_u8(TRAILER_TAG).await?;
+    summary.write_to(writer).await?;
+    writer.flush().await?;
+
+    Ok(summary)
+}
@@ -4,21 +4,52 @@
 
 pub mod config;
 pub mod fadc;
+pub mod fai;
 pub mod fce;
 pub mod fdgse;
 pub mod fsas;
+pub mod ftc;
 
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     time::{Instant, SystemTime},
 };
-use tokio::{fs::File, io::duplex, net::TcpStream};
+use tokio::{
+    fs::File,
+    io::{duplex, AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::TcpStream,
+    sync::mpsc,
+    task::JoinHandle,
+};
 
 // Buffer size doesn't seem to affect performances too much
-// However, it is clear that it affects compression ratio
 pub const BUFFER_SIZE: usize = 1 << 15; // 32 KiB
 pub const DUPLEX_BUFFER_SIZE: usize = 1 << 15; // 32 KiB
 
+// However, it is clear that block size affects compression ratio, so it is set per client
+pub const DEFAULT_BLOCK_SIZE: u32 = 1 << 15; // 32 KiB
+pub const MIN_BLOCK_SIZE: u32 = 1 << 15; // 32 KiB
+pub const MAX_BLOCK_SIZE: u32 = 1 << 22; // 4 MiB
+
+// Bytes in flight between two stages of a pipeline
+const PIPELINE_SIZE: usize = 1 << 19; // 512 KiB
+
+// Number of chunks of `chunk_size` bytes in flight between two stages of a pipeline
+fn pipeline_depth(chunk_size: usize) -> usize {
+    (PIPELINE_SIZE / chunk_size).max(2)
+}
+
+// Smallest number of cores for which CPU-bound stages run on blocking threads by default.
+// With fewer cores, blocking threads compete with the executor for the same cores,
+// which makes backups slower without making the executor more responsive (see benches/pipeline.rs).
+const MIN_OFFLOAD_CORES: usize = 4;
+
+/// Returns whether CPU-bound stages run on blocking threads by default, depending on the number of cores.
+#[must_use]
+pub fn offload_by_default() -> bool {
+    fce::default_workers() >= MIN_OFFLOAD_CORES
+}
+
 pub enum Mode {
     // Operator mode
     Server,
@@ -48,7 +79,13 @@ pub enum SubMode {
 
     // Admin mode
     List,
+    Ls,
     Decompress,
+    Restore,
+    Extract,
+    Export,
+    Import,
+    Verify,
 }
 
 impl TryFrom<String> for SubMode {
@@ -59,40 +96,195 @@ impl TryFrom<String> for SubMode {
             "i" | "init" => Ok(Self::Init),
             "s" | "start" => Ok(Self::Start),
             "l" | "list" => Ok(Self::List),
+            "ls" => Ok(Self::Ls),
             "dc" | "decompress" => Ok(Self::Decompress),
+            "r" | "restore" => Ok(Self::Restore),
+            "x" | "extract" => Ok(Self::Extract),
+            "e" | "export" => Ok(Self::Export),
+            "im" | "import" => Ok(Self::Import),
+            "v" | "verify" => Ok(Self::Verify),
             _ => Err("Invalid submode".to_string()),
         }
     }
 }
 
+// Runs the CPU-bound `f` on the items received from `rx` in a blocking task, so that it does not stall the executor.
+// Items already waiting are processed as a batch, whose outputs are sent together to `tx`.
+// The stage stops at the first error, after sending it.
+fn spawn_blocking_stage<T, F>(
+    mut rx: mpsc::Receiver<T>,
+    tx: mpsc::Sender<std::io::Result<Vec<u8>>>,
+    mut f: F,
+) -> JoinHandle<()>
+where
+    T: Send + 'static,
+    F: FnMut(T, &mut Vec<u8>) -> std::io::Result<()> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        while let Some(item) = rx.blocking_recv() {
+            let mut batch = Vec::new();
+            let mut result = f(item, &mut batch);
+            for _ in 1..rx.max_capacity() {
+                match rx.try_recv() {
+                    Ok(item) if result.is_ok() => result = f(item, &mut batch),
+                    _ => break,
+                }
+            }
+
+            let failed = result.is_err();
+            if tx.blocking_send(result.map(|()| batch)).is_err() || failed {
+                return;
+            }
+        }
+    })
+}
+
+// Runs the CPU-bound `f` on the items received from `rx` and writes their outputs, until `rx` is closed.
+// With `offload`, `f` runs in a blocking stage, otherwise in the current task.
+async fn run_stage<T, F, W>(
+    mut rx: mpsc::Receiver<T>,
+    writer: &mut W,
+    offload: bool,
+    mut f: F,
+) -> std::io::Result<()>
+where
+    T: Send + 'static,
+    F: FnMut(T, &mut Vec<u8>) -> std::io::Result<()> + Send + 'static,
+    W: AsyncWrite + Unpin + Send,
+{
+    if offload {
+        let (tx, batches) = mpsc::channel(rx.max_capacity());
+        let stage = spawn_blocking_stage(rx, tx, f);
+        write_batches(batches, writer).await?;
+        return Ok(stage.await?);
+    }
+
+    let mut output = Vec::new();
+    while let Some(item) = rx.recv().await {
+        output.clear();
+        f(item, &mut output)?;
+        writer.write_all(&output).await?;
+    }
+
+    writer.flush().await
+}
+
+// Writes the batches output by a blocking stage, until the stage ends
+async fn write_batches<W>(
+    mut rx: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    writer: &mut W,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    while let Some(batch) = rx.recv().await {
+        writer.write_all(&batch?).await?;
+    }
+
+    writer.flush().await
+}
+
 pub struct Client {
     pub hostname: String,
     pub info: config::ClientInfo,
 }
 
+// Compresses the stream into a new archive, and writes its index alongside.
+// As set by `storage`, blocks are compressed in parallel, and the content of files with
+// an incompressible extension is stored without compression.
+// ## Errors
+// This function returns an error if the stream cannot be read or the archive cannot be written.
+pub async fn store_backup<R>(
+    reader: &mut R,
+    archive_path: &Path,
+    header: &fce::ArchiveHeader,
+    storage: &config::StorageConfig,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let index_path = fai::index_path(archive_path);
+    let mut file = File::create(archive_path).await?;
+    let mut index_file = BufWriter::new(File::create(&index_path).await?);
+    log::trace!("Backup file created: {:?}", archive_path);
+
+    let depth = pipeline_depth(header.block_size as usize);
+    let (blocks_tx, blocks_rx) = mpsc::channel(depth);
+    // The backup is still usable without its index
+    let index_handle = tokio::spawn(async move {
+        if let Err(e) = fai::index_stream(blocks_rx, &mut index_file).await {
+            log::warn!("Index could not be written for {:?}: {}", index_path, e);
+            let _ = tokio::fs::remove_file(index_path).await;
+        }
+    });
+
+    let (chunks_tx, chunks_rx) = mpsc::channel(depth);
+    let hint = async move {
+        fadc::hint_stream(
+            reader,
+            &chunks_tx,
+            &storage.incompressible_extensions,
+            header.block_size as usize,
+        )
+        .await
+    };
+    let compress = fce::compress_chunks(
+        chunks_rx,
+        &mut file,
+        header,
+        Some(&blocks_tx),
+        storage.compression_workers,
+    );
+    tokio::try_join!(hint, compress)?;
+
+    drop(blocks_tx);
+    index_handle.await?;
+
+    Ok(())
+}
+
 pub async fn handle_client(
     client: Client,
     mut stream: TcpStream,
     backup_dir: PathBuf,
+    server_hostname: String,
 ) -> std::io::Result<()> {
-    fsas::send_and_verify_challenge(&mut stream, &client.info.keypair.verifying_key).await?;
-    log::debug!("Client {} verified", client.hostname);
+    let shared_secret = fsas::authenticate_client(
+        &mut stream,
+        &client.hostname,
+        &server_hostname,
+        &client.info.keypair,
+    )
+    .await?;
+    log::debug!("Client {} authenticated", client.hostname);
 
-    fsas::receive_and_answer_challenge(&mut stream, &client.info.keypair.signing_key).await?;
-    log::debug!("Authenticated to client {}", client.hostname);
+    let session = fdgse::answer_session(
+        &mut stream,
+        client.info.storage.block_size,
+        &shared_secret,
+        client.info.psk.as_ref(),
+    )
+    .await?;
+    log::debug!(
+        "Block size for {}: {} bytes",
+        client.hostname,
+        session.block_size
+    );
 
-    let dirname = format!("{}/{}", backup_dir.to_str().unwrap(), client.hostname);
-    let filename = format!(
-        "{}/{}.lz4",
-        dirname,
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let header = fce::ArchiveHeader::new(
+        &client.hostname,
+        i64::try_from(timestamp).unwrap(),
+        client.info.storage.compression,
+        session.block_size,
     );
+
+    let dirname = format!("{}/{}", backup_dir.to_str().unwrap(), client.hostname);
+    let filename = format!("{dirname}/{timestamp}.{}", fce::ARCHIVE_EXTENSION);
     tokio::fs::create_dir_all(dirname).await?;
-    let mut file = File::create(filename).await?;
-    log::trace!("Backfup file created for {}", client.hostname);
 
     let start = Instant::now();
     log::info!("Backup started for {}", client.hostname);
@@ -100,25 +292,48 @@ pub async fn handle_client(
     let (mut tx, mut rx) = duplex(DUPLEX_BUFFER_SIZE);
 
     let cipher_handle = tokio::spawn(async move {
-        Box::pin(fdgse::decipher_stream(
-            &mut stream,
-            &mut tx,
-            client.info.cipher_key,
-        ))
-        .await
-        .expect("Error deciphering data");
+        Box::pin(fdgse::decipher_stream(&mut stream, &mut tx, &session)).await
     });
+    let archive_path = PathBuf::from(&filename);
     let compress_handle = tokio::spawn(async move {
-        Box::pin(fce::compress_stream(&mut rx, &mut file))
-            .await
-            .expect("Error compressing data");
+        Box::pin(store_backup(
+            &mut rx,
+            filename.as_ref(),
+            &header,
+            &client.info.storage,
+        ))
+        .await
     });
 
-    cipher_handle.await?;
-    compress_handle.await?;
+    let deciphered = cipher_handle.await?;
+    let stored = compress_handle.await?;
+
+    // A backup without its last frame is incomplete, it is not kept
+    if let Err(e) = deciphered.and(stored) {
+        log::error!(
+            "Backup failed for {}, discarding {:?}: {}",
+            client.hostname,
+            archive_path,
+            e
+        );
+        discard_backup(&archive_path).await;
+        return Err(e);
+    }
 
     let duration = start.elapsed();
     log::info!("Backup finished for {} in {:?}", client.hostname, duration);
 
     Ok(())
 }
+
+// Removes an incomplete archive along with its index, whichever of them exists
+pub async fn discard_backup(archive_path: &Path) {
+    for path in [fai::index_path(archive_path), archive_path.to_path_buf()] {
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Could not remove {:?}: {}", path, e);
+            }
+            _ => (),
+        }
+    }
+}
@@ -14,57 +14,62 @@
 //! 3. The client sends the files to be backed up to the server
 //! 4. The server compresseses them on the fly
 
-use std::{io, path::PathBuf};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
 
-use forgedbackup::config::ClientInfo;
-use forgedbackup::{config, fadc, fce, fdgse, fsas, Client};
+use forgedbackup::{config, fadc, fai, fce, fdgse, fsas, ftc, Client};
 use forgedbackup::{Mode, SubMode};
 
 async fn start_server(config: &config::ServerConfig) -> io::Result<()> {
     let listener = TcpListener::bind(config.listening_socker_addr).await?;
     log::info!("Server listening on {}", config.listening_socker_addr);
 
+    let client_infos = Arc::new(config.client_infos.clone());
+
     loop {
         let (mut stream, peer_addr) = listener.accept().await?;
         log::debug!("Incoming connexion from {}", peer_addr);
 
-        let mut hostname = [0u8; 256];
-        let amount_read = stream.read(&mut hostname).await?;
-        let hostname = String::from_utf8(hostname[..amount_read].to_vec()).unwrap();
-        let hostname = hostname.trim_matches(char::from(0));
-        log::trace!("Received hostname: {}", hostname);
-
-        let client_info = config.client_infos.get(hostname).expect("Client not found");
-        log::trace!("Client found: {}", hostname);
-
-        let signing_key = client_info.keypair.signing_key.clone();
-        let verifying_key = client_info.keypair.verifying_key;
-        let cipher_key = client_info.cipher_key;
-        let hostname = hostname.to_string();
+        // Clients are handled in their own task from the start, so that none of them can stall the others
+        let client_infos = client_infos.clone();
         let backup_dir = config.backup_dir.clone();
+        let server_hostname = config.hostname.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = {
-                log::trace!("Handling client {}", hostname);
-                forgedbackup::handle_client(
-                    Client {
-                        hostname,
-                        info: ClientInfo {
-                            keypair: fsas::KeyPair {
-                                signing_key,
-                                verifying_key,
-                            },
-                            cipher_key,
-                        },
-                    },
-                    stream,
-                    backup_dir,
-                )
-                .await
-            } {
+            let mut hostname = [0u8; 256];
+            let amount_read = match stream.read(&mut hostname).await {
+                Ok(amount_read) => amount_read,
+                Err(e) => {
+                    log::error!("Error reading hostname from {}: {}", peer_addr, e);
+                    return;
+                }
+            };
+            let hostname = String::from_utf8_lossy(&hostname[..amount_read]);
+            let hostname = hostname.trim_matches(char::from(0)).to_string();
+            log::trace!("Received hostname: {}", hostname);
+
+            let Some(info) = client_infos.get(&hostname).cloned() else {
+                log::error!("Client not found: {}", hostname);
+                return;
+            };
+            log::trace!("Client found: {}", hostname);
+
+            log::trace!("Handling client {}", hostname);
+            if let Err(e) = forgedbackup::handle_client(
+                Client { hostname, info },
+                stream,
+                backup_dir,
+                server_hostname,
+            )
+            .await
+            {
                 log::error!("Error handling client: {}", e);
             }
         });
@@ -75,38 +80,40 @@ async fn start_client(config: &config::ClientConfig) -> io::Result<()> {
     let mut backup_made = false;
 
     for server_info in config.servers.clone() {
-        let result: io::Result<()> = {
+        let result: io::Result<()> = async {
             let mut stream = TcpStream::connect(server_info.addr).await?;
             log::debug!("Connected to server {}.", server_info.hostname);
 
             stream.write_all(config.hostname.as_bytes()).await?;
             log::trace!("Hostname sent: {}", config.hostname);
 
-            fsas::receive_and_answer_challenge(&mut stream, &server_info.keypair.signing_key)
-                .await?;
-            log::debug!("Authenticated to server {}", server_info.hostname);
-
-            fsas::send_and_verify_challenge(&mut stream, &server_info.keypair.verifying_key)
-                .await?;
-            log::debug!("Server {} verified", server_info.hostname);
+            let shared_secret = fsas::authenticate_server(
+                &mut stream,
+                &config.hostname,
+                &server_info.hostname,
+                &server_info.keypair,
+            )
+            .await?;
+            log::debug!("Server {} authenticated", server_info.hostname);
+
+            let mut session =
+                fdgse::propose_session(&mut stream, &shared_secret, server_info.psk.as_ref())
+                    .await?;
+            session.rekey_after = config.rekey_after;
+            log::debug!("Block size: {} bytes", session.block_size);
 
             let start = std::time::Instant::now();
             log::info!("Starting backup on server {}", server_info.hostname);
 
-            let dir_path = config.backed_up_dir.clone();
             let (mut tx, mut rx) = duplex(forgedbackup::DUPLEX_BUFFER_SIZE);
+            // Dropping the pipe at the end of the crawl ends the stream
+            let crawl = async move {
+                fadc::read_dir(&config.backed_up_dirs, &config.crawl_options, &mut tx).await
+            };
 
-            let dir_handle = tokio::spawn(async move {
-                fadc::read_dir(dir_path, &mut tx).await.unwrap();
-            });
-            let cipher_handle = tokio::spawn(async move {
-                fdgse::cipher_stream(&mut rx, &mut stream, &server_info.cipher_key)
-                    .await
-                    .unwrap();
-            });
-
-            dir_handle.await?;
-            cipher_handle.await?;
+            // A failed crawl must not let the stream end with its last frame
+            let (summary, ()) =
+                tokio::try_join!(crawl, fdgse::cipher_stream(&mut rx, &mut stream, &session),)?;
 
             let duration = start.elapsed();
             log::info!(
@@ -114,11 +121,28 @@ async fn start_client(config: &config::ClientConfig) -> io::Result<()> {
                 server_info.hostname,
                 duration
             );
+            if !summary.changed.is_empty() {
+                log::warn!(
+                    "{} files changed during backup on server {}: {:?}",
+                    summary.changed.len(),
+                    server_info.hostname,
+                    summary.changed
+                );
+            }
+            for error in &summary.errors {
+                log::warn!(
+                    "Could not back up {:?} on server {}: {}",
+                    error.path,
+                    server_info.hostname,
+                    error.message
+                );
+            }
 
             backup_made = true;
 
             Ok(())
-        };
+        }
+        .await;
 
         if let Err(e) = result {
             log::error!(
@@ -136,12 +160,224 @@ async fn start_client(config: &config::ClientConfig) -> io::Result<()> {
     Ok(())
 }
 
+// Backups of a client, from the oldest to the most recent
+async fn list_backups(client_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut backups = Vec::new();
+
+    let mut entries = tokio::fs::read_dir(client_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let is_archive = path.extension().is_some_and(|extension| {
+            extension == fce::ARCHIVE_EXTENSION || extension == fce::LEGACY_ARCHIVE_EXTENSION
+        });
+        if is_archive {
+            backups.push(path);
+        }
+    }
+    backups.sort();
+
+    Ok(backups)
+}
+
+fn print_entry(entry: &fadc::Entry) -> io::Result<()> {
+    use std::io::Write;
+
+    let size = if entry.kind == fadc::EntryKind::File {
+        entry.size.to_string()
+    } else {
+        "-".to_string()
+    };
+
+    let mut stdout = io::stdout().lock();
+    write!(
+        stdout,
+        "{}{:04o} {:>5} {:>5} {:>12} {} {}",
+        entry.kind.symbol(),
+        entry.metadata.mode & 0o7777,
+        entry.metadata.uid,
+        entry.metadata.gid,
+        size,
+        entry.metadata.mtime,
+        entry.path.display()
+    )?;
+    if let Some(target) = &entry.target {
+        write!(stdout, " -> {}", target.display())?;
+    }
+    writeln!(stdout)
+}
+
+// Decompresses a backup in the background, returning the stream of its entries
+async fn decompress_backup(
+    backup: &Path,
+) -> io::Result<(DuplexStream, JoinHandle<io::Result<()>>)> {
+    let (header, mut archive) = fce::open_archive(backup).await?;
+    log::debug!(
+        "Backup of {:?} at {}, format version {}, compressed with {:?}",
+        header.hostname,
+        header.timestamp,
+        header.version,
+        header.compression
+    );
+
+    let (mut tx, rx) = duplex(forgedbackup::DUPLEX_BUFFER_SIZE);
+    if !header.is_legacy() {
+        let decompress_handle =
+            tokio::spawn(
+                async move { fce::decompress_stream(&mut archive, &mut tx, &header).await },
+            );
+        return Ok((rx, decompress_handle));
+    }
+
+    // Legacy archives hold an older stream, converted on the fly.
+    // Times of their files were not recorded, the time of the backup is used instead.
+    let timestamp = fadc::Timestamp::from(tokio::fs::metadata(backup).await?.modified()?);
+    let decompress_handle = tokio::spawn(async move {
+        let (mut legacy_tx, mut legacy_rx) = duplex(forgedbackup::DUPLEX_BUFFER_SIZE);
+        let decompress =
+            async move { fce::decompress_stream(&mut archive, &mut legacy_tx, &header).await };
+        let upgrade = fadc::upgrade_legacy_stream(
+            &mut legacy_rx,
+            &mut tx,
+            timestamp,
+            fadc::DEFAULT_MAX_PATH_LEN,
+        );
+        tokio::try_join!(decompress, upgrade).map(|_| ())
+    });
+
+    Ok((rx, decompress_handle))
+}
+
+// Lists the entries of a backup, using its index if there is one
+async fn list_entries(
+    backup: &Path,
+    filter: &fadc::PathFilter,
+) -> io::Result<Option<fadc::CrawlSummary>> {
+    let print_matching = |entry: &fadc::Entry| {
+        if filter.matches(&entry.path) {
+            print_entry(entry)?;
+        }
+        Ok(())
+    };
+
+    let index_path = fai::index_path(backup);
+    if tokio::fs::try_exists(&index_path).await? {
+        let mut index = tokio::io::BufReader::new(tokio::fs::File::open(index_path).await?);
+        return fai::walk(&mut index, fadc::DEFAULT_MAX_PATH_LEN, |entry| {
+            print_matching(&entry.entry)
+        })
+        .await;
+    }
+
+    let (mut rx, decompress_handle) = decompress_backup(backup).await?;
+    let summary = fadc::walk(&mut rx, fadc::DEFAULT_MAX_PATH_LEN, print_matching).await?;
+    decompress_handle.await??;
+
+    Ok(summary)
+}
+
+// Finds the hardlinks selected by the options whose target is not, by walking the whole backup,
+// as their target comes first
+async fn detached_links(
+    backup: &Path,
+    options: &fadc::RestoreOptions,
+) -> io::Result<fadc::DetachedLinks> {
+    let mut links = fadc::DetachedLinks::default();
+    let (mut rx, decompress_handle) = decompress_backup(backup).await?;
+    fadc::walk(&mut rx, options.max_path_len, |entry| {
+        links.add(entry, options);
+        Ok(())
+    })
+    .await?;
+    decompress_handle.await??;
+
+    Ok(links)
+}
+
+// Verifies that a backup can be entirely read, checking its checksums and digest if it has them.
+// Returns the header of the backup, along with its corrupted blocks.
+async fn verify_backup(
+    backup: &Path,
+) -> io::Result<(fce::ArchiveHeader, Vec<fce::CorruptedBlock>)> {
+    // Every block is checked first, so that all corrupted blocks are reported
+    let (header, mut archive) = fce::open_archive(backup).await?;
+    let corrupted = fce::scan_blocks(&mut archive, &header).await?;
+    if !corrupted.is_empty() {
+        return Ok((header, corrupted));
+    }
+
+    let (mut rx, decompress_handle) = decompress_backup(backup).await?;
+    let walked = fadc::walk(&mut rx, fadc::DEFAULT_MAX_PATH_LEN, |_| Ok(())).await;
+    drop(rx);
+
+    // Decompression errors explain walk errors
+    match decompress_handle.await? {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+        _ => (),
+    }
+    if walked?.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Backup has no summary, it is incomplete",
+        ));
+    }
+
+    Ok((header, corrupted))
+}
+
+fn restore_options(flags: &[String]) -> fadc::RestoreOptions {
+    let flag_value = |name: &str| {
+        flags
+            .iter()
+            .find_map(|flag| flag.strip_prefix(name)?.strip_prefix('='))
+    };
+
+    let mut options = fadc::RestoreOptions::default();
+    if flags.iter().any(|flag| flag == "--no-owner") {
+        options.preserve_ownership = false;
+    }
+    if let Some(max_path_len) = flag_value("--max-path-len") {
+        options.max_path_len = max_path_len.parse().expect("Invalid maximum path length");
+    }
+    options.source = flag_value("--source").map(str::to_string);
+
+    let paths: Vec<&str> = flags
+        .iter()
+        .filter_map(|flag| flag.strip_prefix("--path="))
+        .collect();
+    options.paths = fadc::PathFilter::new(&paths).expect("Invalid path pattern");
+
+    options
+}
+
+// Flags taking a value, given either as `--flag=value` or as `--flag value`
+const VALUE_FLAGS: &[&str] = &["--path", "--source", "--max-path-len", "--format", "--time"];
+
+// Splits the command line between flags, all normalized as `--flag[=value]`, and positional arguments
+fn split_args(mut command_line: impl Iterator<Item = String>) -> (Vec<String>, Vec<String>) {
+    let mut flags = Vec::new();
+    let mut args = Vec::new();
+
+    while let Some(arg) = command_line.next() {
+        if VALUE_FLAGS.contains(&arg.as_str()) {
+            let value = command_line
+                .next()
+                .unwrap_or_else(|| panic!("Missing value for {arg}"));
+            flags.push(format!("{arg}={value}"));
+        } else if arg.starts_with("--") {
+            flags.push(arg);
+        } else {
+            args.push(arg);
+        }
+    }
+
+    (flags, args)
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     pretty_env_logger::init();
 
-    let args = std::env::args().collect::<Vec<String>>();
-
+    let (flags, args) = split_args(std::env::args());
     if args.len() < 3 {
         panic!("Usage: {} <server|client|admin> <init|start>", args[0]);
     }
@@ -207,7 +443,19 @@ async fn main() -> std::io::Result<()> {
             }
             SubMode::Start => {
                 let client_config = config::ClientConfig::read("config.toml");
-                start_client(&client_config).await?;
+                if flags.iter().any(|flag| flag == "--dry-run") {
+                    let summary = fadc::list_dir(
+                        &client_config.backed_up_dirs,
+                        &client_config.crawl_options,
+                        &mut tokio::io::stdout(),
+                    )
+                    .await?;
+                    for error in &summary.errors {
+                        log::warn!("Could not read {:?}: {}", error.path, error.message);
+                    }
+                } else {
+                    start_client(&client_config).await?;
+                }
             }
             _ => panic!("Invalid submode for operator mode."),
         },
@@ -220,10 +468,9 @@ async fn main() -> std::io::Result<()> {
                     let filename = server.file_name();
                     let filename = filename.to_str().unwrap();
                     println!("Backups for {}:", filename);
-                    let backups = std::fs::read_dir(server.path())?;
-                    for (i, backup) in backups.enumerate() {
-                        let backup = backup?;
-                        let metadata = backup.metadata()?;
+                    let backups = list_backups(&server.path()).await?;
+                    for (i, backup) in backups.iter().enumerate() {
+                        let metadata = tokio::fs::metadata(backup).await?;
                         let size = metadata.len();
                         let last_modified = metadata.modified()?.elapsed().unwrap();
 
@@ -250,7 +497,7 @@ async fn main() -> std::io::Result<()> {
 
                 if args.len() < 5 {
                     panic!(
-                        "Usage: {} admin decompress <server> <backup-number> [dest-dir]",
+                        "Usage: {} admin decompress <server> <backup-number> [dest-dir] [--source=<name>] [--no-owner] [--max-path-len=<bytes>]",
                         args[0]
                     );
                 }
@@ -258,20 +505,95 @@ async fn main() -> std::io::Result<()> {
                 let server = args[3].clone();
                 let backup_dir = server_config.backup_dir.join(server);
 
-                let mut backup_number = args[4].parse::<usize>().expect("Invalid backup number");
+                let backup_number = args[4].parse::<usize>().expect("Invalid backup number");
+
+                let backups = list_backups(&backup_dir).await?;
+                let backup = backups.get(backup_number).expect("Backup not found");
 
-                let mut backups = tokio::fs::read_dir(&backup_dir).await.unwrap();
+                let output_dir = PathBuf::from(if args.len() == 6 {
+                    args[5].clone()
+                } else {
+                    "./decompressed".to_string()
+                });
+
+                let options = restore_options(&flags);
+
+                // Hardlinks of the source may link to files of another source
+                let links = if options.source.is_some() {
+                    detached_links(backup, &options).await?
+                } else {
+                    fadc::DetachedLinks::default()
+                };
+
+                let (mut rx, decompress_handle) = decompress_backup(backup).await?;
+                // The stream is dropped as soon as the restore ends, so that decompression does not wait for it
+                let restore = async move {
+                    fadc::write_selected(&mut rx, output_dir, &options, links, None).await
+                };
+                let (restored, decompressed) = tokio::join!(restore, decompress_handle);
+
+                // Decompression stops once the stream is no longer read, so the restore error comes first,
+                // unless the stream ended early because decompression failed
+                match (restored, decompressed?) {
+                    (Err(e), Err(decompressed)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Err(decompressed);
+                    }
+                    (restored, decompressed) => {
+                        restored?;
+                        decompressed?;
+                    }
+                }
+            }
+            SubMode::Ls => {
+                let server_config = config::ServerConfig::read("config.toml");
+
+                if args.len() < 5 {
+                    panic!(
+                        "Usage: {} admin ls <server> <backup-number> [pattern...]",
+                        args[0]
+                    );
+                }
 
-                let mut backup = None;
-                while let Some(entry) = backups.next_entry().await? {
-                    if backup_number == 0 {
-                        backup = Some(entry);
-                        break;
+                let backup_dir = server_config.backup_dir.join(&args[3]);
+                let backup_number = args[4].parse::<usize>().expect("Invalid backup number");
+
+                let backups = list_backups(&backup_dir).await?;
+                let backup = backups.get(backup_number).expect("Backup not found");
+
+                let filter = fadc::PathFilter::new(&args[5..])?;
+
+                let summary = list_entries(backup, &filter).await?;
+                match summary {
+                    Some(summary) => {
+                        for path in &summary.changed {
+                            log::warn!("File changed during backup: {:?}", path);
+                        }
+                        for error in &summary.errors {
+                            log::warn!(
+                                "Entry could not be backed up: {:?}: {}",
+                                error.path,
+                                error.message
+                            );
+                        }
                     }
-                    backup_number -= 1;
+                    None => log::warn!("Backup has no summary, it may be incomplete"),
+                }
+            }
+            SubMode::Restore => {
+                let server_config = config::ServerConfig::read("config.toml");
+
+                if args.len() < 5 {
+                    panic!(
+                        "Usage: {} admin restore <server> <backup-number> [dest-dir] --path <pattern>... [--source=<name>] [--no-owner] [--max-path-len=<bytes>]",
+                        args[0]
+                    );
                 }
 
-                let backup = tokio::fs::read(backup.expect("Backup not found").path()).await?;
+                let backup_dir = server_config.backup_dir.join(&args[3]);
+                let backup_number = args[4].parse::<usize>().expect("Invalid backup number");
+
+                let backups = list_backups(&backup_dir).await?;
+                let backup = backups.get(backup_number).expect("Backup not found");
 
                 let output_dir = PathBuf::from(if args.len() == 6 {
                     args[5].clone()
@@ -279,19 +601,250 @@ async fn main() -> std::io::Result<()> {
                     "./decompressed".to_string()
                 });
 
-                let (mut tx, mut rx) = tokio::io::duplex(forgedbackup::DUPLEX_BUFFER_SIZE);
+                let options = restore_options(&flags);
+                // Without patterns, everything would be restored
+                assert!(
+                    !options.paths.selects_everything(),
+                    "No path to restore, give at least one --path <pattern>"
+                );
 
-                let decompress_handle = tokio::spawn(async move {
-                    fce::decompress_stream(&mut backup.as_slice(), &mut tx)
-                        .await
-                        .unwrap();
-                });
-                let dir_handle = tokio::spawn(async move {
-                    fadc::write_dir(&mut rx, output_dir).await.unwrap();
+                let index_path = fai::index_path(backup);
+                let count = if tokio::fs::try_exists(&index_path).await? {
+                    let index = tokio::fs::File::open(index_path).await?;
+                    let mut index = tokio::io::BufReader::new(index);
+                    fai::restore(backup, &mut index, output_dir, &options).await?
+                } else {
+                    log::info!("Backup has no index, reading the whole archive");
+                    let links = detached_links(backup, &options).await?;
+                    let (mut rx, decompress_handle) = decompress_backup(backup).await?;
+                    let count =
+                        fadc::write_selected(&mut rx, output_dir, &options, links, None).await?;
+                    decompress_handle.await??;
+                    count
+                };
+
+                log::info!("Restored {} entries", count);
+            }
+            SubMode::Export => {
+                let server_config = config::ServerConfig::read("config.toml");
+
+                if args.len() < 5 {
+                    panic!(
+                        "Usage: {} admin export <server> <backup-number> [--format=tar] [--max-path-len=<bytes>]",
+                        args[0]
+                    );
+                }
+
+                let format = flags
+                    .iter()
+                    .find_map(|flag| flag.strip_prefix("--format="))
+                    .unwrap_or("tar");
+                assert!(format == "tar", "Unsupported export format: {format}");
+
+                let backup_dir = server_config.backup_dir.join(&args[3]);
+                let backup_number = args[4].parse::<usize>().expect("Invalid backup number");
+
+                let backups = list_backups(&backup_dir).await?;
+                let backup = backups.get(backup_number).expect("Backup not found");
+
+                let options = restore_options(&flags);
+
+                let (mut rx, decompress_handle) = decompress_backup(backup).await?;
+
+                let mut stdout = tokio::io::BufWriter::new(tokio::io::stdout());
+                let summary =
+                    ftc::export_stream(&mut rx, &mut stdout, options.max_path_len).await?;
+                decompress_handle.await??;
+
+                for error in summary.iter().flat_map(|summary| &summary.errors) {
+                    log::warn!(
+                        "Entry could not be backed up: {:?}: {}",
+                        error.path,
+                        error.message
+                    );
+                }
+            }
+            SubMode::Import => {
+                let server_config = config::ServerConfig::read("config.toml");
+
+                if args.len() < 5 {
+                    panic!(
+                        "Usage: {} admin import <server> <file.tar|-> [--time=<unix-seconds>]",
+                        args[0]
+                    );
+                }
+
+                let backup_dir = server_config.backup_dir.join(&args[3]);
+                let tar_path = &args[4];
+
+                // Backups are named after their time, which defaults to the one of the archive
+                let time = match flags.iter().find_map(|flag| flag.strip_prefix("--time=")) {
+                    Some(time) => time.parse::<u64>().expect("Invalid time"),
+                    None if tar_path == "-" => std::time::SystemTime::now()
+                        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                    None => tokio::fs::metadata(tar_path)
+                        .await?
+                        .modified()?
+                        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                };
+
+                tokio::fs::create_dir_all(&backup_dir).await?;
+                let archive_path = backup_dir.join(format!("{time}.{}", fce::ARCHIVE_EXTENSION));
+                assert!(
+                    !tokio::fs::try_exists(&archive_path).await?,
+                    "A backup already exists at this time: {}",
+                    archive_path.display()
+                );
+
+                let reader: Box<dyn std::io::Read + Send> = if tar_path == "-" {
+                    Box::new(io::stdin())
+                } else {
+                    Box::new(std::fs::File::open(tar_path)?)
+                };
+                let reader = io::BufReader::new(reader);
+
+                let (mut tx, mut rx) = duplex(forgedbackup::DUPLEX_BUFFER_SIZE);
+
+                let import_handle =
+                    tokio::spawn(async move { ftc::import_stream(reader, &mut tx).await });
+                let storage = server_config
+                    .client_infos
+                    .get(&args[3])
+                    .map_or(&server_config.storage, |info| &info.storage);
+                let mut header = fce::ArchiveHeader::new(
+                    &args[3],
+                    i64::try_from(time).expect("Invalid time"),
+                    storage.compression,
+                    storage.block_size,
+                );
+                header.flags |= fce::FLAG_IMPORTED;
+                let stored =
+                    forgedbackup::store_backup(&mut rx, &archive_path, &header, storage).await;
+                // The import cannot block on a stream that is no longer read
+                drop(rx);
+
+                let imported = match (import_handle.await?, stored) {
+                    // The import stops once the stream is no longer read
+                    (Err(e), Err(stored)) if e.kind() == io::ErrorKind::BrokenPipe => Err(stored),
+                    (imported, stored) => imported.and_then(|summary| stored.map(|()| summary)),
+                };
+                match imported {
+                    Ok(summary) => {
+                        for error in &summary.errors {
+                            log::warn!(
+                                "Entry could not be imported: {:?}: {}",
+                                error.path,
+                                error.message
+                            );
+                        }
+                        log::info!("Imported {}", archive_path.display());
+                    }
+                    Err(e) => {
+                        forgedbackup::discard_backup(&archive_path).await;
+                        return Err(e);
+                    }
+                }
+            }
+            SubMode::Extract => {
+                let server_config = config::ServerConfig::read("config.toml");
+
+                if args.len() < 6 {
+                    panic!(
+                        "Usage: {} admin extract <server> <backup-number> <path> [dest-dir] [--source=<name>] [--no-owner] [--max-path-len=<bytes>]",
+                        args[0]
+                    );
+                }
+
+                let backup_dir = server_config.backup_dir.join(&args[3]);
+                let backup_number = args[4].parse::<usize>().expect("Invalid backup number");
+                let path = PathBuf::from(&args[5]);
+
+                let backups = list_backups(&backup_dir).await?;
+                let backup = backups.get(backup_number).expect("Backup not found");
+
+                let output_dir = PathBuf::from(if args.len() == 7 {
+                    args[6].clone()
+                } else {
+                    "./decompressed".to_string()
                 });
 
-                decompress_handle.await?;
-                dir_handle.await?;
+                let options = restore_options(&flags);
+
+                let index = tokio::fs::File::open(fai::index_path(backup))
+                    .await
+                    .expect("Backup has no index, use decompress instead");
+                let mut index = tokio::io::BufReader::new(index);
+
+                let entry = fai::extract(backup, &mut index, &path, output_dir, &options).await?;
+                log::info!(
+                    "Extracted {:?}",
+                    entry.expect("Entry not found in backup").path
+                );
+            }
+            SubMode::Verify => {
+                let server_config = config::ServerConfig::read("config.toml");
+
+                let client_dirs = if args.len() > 3 {
+                    vec![server_config.backup_dir.join(&args[3])]
+                } else {
+                    let mut client_dirs = Vec::new();
+                    let mut backup_dir = tokio::fs::read_dir(&server_config.backup_dir).await?;
+                    while let Some(client) = backup_dir.next_entry().await? {
+                        client_dirs.push(client.path());
+                    }
+                    client_dirs.sort();
+                    client_dirs
+                };
+                let backup_number = args
+                    .get(4)
+                    .map(|number| number.parse::<usize>().expect("Invalid backup number"));
+
+                let mut corrupted = 0;
+                for client_dir in client_dirs {
+                    let client = client_dir
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned();
+                    let backups = list_backups(&client_dir).await?;
+                    let backups: Vec<(usize, &PathBuf)> = match backup_number {
+                        Some(number) => {
+                            vec![(number, backups.get(number).expect("Backup not found"))]
+                        }
+                        None => backups.iter().enumerate().collect(),
+                    };
+
+                    for (i, backup) in backups {
+                        match verify_backup(backup).await {
+                            Ok((_, blocks)) if !blocks.is_empty() => {
+                                for block in blocks {
+                                    println!(
+                                        "{} [{}] CORRUPTED: block {} at offset {}: {}",
+                                        client, i, block.number, block.offset, block.error
+                                    );
+                                }
+                                corrupted += 1;
+                            }
+                            Ok((header, _)) if header.is_legacy() => {
+                                println!("{} [{}] OK (legacy archive, no checksums)", client, i);
+                            }
+                            Ok(_) => println!("{} [{}] OK", client, i),
+                            Err(e) => {
+                                println!("{} [{}] CORRUPTED: {}", client, i, e);
+                                corrupted += 1;
+                            }
+                        }
+                    }
+                }
+
+                if corrupted > 0 {
+                    log::error!("{} corrupted backup(s)", corrupted);
+                    std::process::exit(1);
+                }
             }
             _ => panic!("Invalid submode for admin mode."),
         },
@@ -0,0 +1,152 @@
+use forgedbackup::{fdgse, MIN_BLOCK_SIZE};
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+
+// Flags of a frame, as sent on the wire
+const FRAME_LAST: u8 = 1 << 0;
+const FRAME_REKEY: u8 = 1 << 1;
+
+// Enough data for several full frames and a partial one
+fn sample_data() -> Vec<u8> {
+    (0..100_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+async fn cipher(session: &fdgse::Session, data: &[u8]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    fdgse::cipher_stream(&mut &data[..], &mut stream, session)
+        .await
+        .unwrap();
+    stream
+}
+
+async fn decipher(session: &fdgse::Session, stream: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    fdgse::decipher_stream(&mut &stream[..], &mut data, session).await?;
+    Ok(data)
+}
+
+// Splits a ciphered stream into its frames, each made of its flags, its length and its cipher text
+fn frames(mut stream: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    while !stream.is_empty() {
+        let len = u64::from_le_bytes(stream[1..9].try_into().unwrap());
+        let (frame, rest) = stream.split_at(9 + usize::try_from(len).unwrap());
+        frames.push(frame.to_vec());
+        stream = rest;
+    }
+    frames
+}
+
+#[tokio::test]
+async fn stream_round_trips() {
+    let data = sample_data();
+
+    for offload in [false, true] {
+        let mut session = fdgse::Session::new(MIN_BLOCK_SIZE, fdgse::generate_key());
+        session.offload = offload;
+        let stream = cipher(&session, &data).await;
+
+        let frames = frames(&stream);
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames.last().unwrap()[0], FRAME_LAST);
+        assert_eq!(decipher(&session, &stream).await.unwrap(), data);
+    }
+}
+
+#[tokio::test]
+async fn truncated_stream_is_rejected() {
+    let session = fdgse::Session::new(MIN_BLOCK_SIZE, fdgse::generate_key());
+    let stream = cipher(&session, &sample_data()).await;
+    let frames = frames(&stream);
+
+    // In the middle of a frame
+    let error = decipher(&session, &stream[..frames[0].len() + 100])
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), UnexpectedEof);
+
+    // Between frames, without the last one
+    let error = decipher(&session, &frames[..frames.len() - 1].concat())
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), UnexpectedEof);
+}
+
+#[tokio::test]
+async fn reordered_frame_is_rejected() {
+    let session = fdgse::Session::new(MIN_BLOCK_SIZE, fdgse::generate_key());
+    let mut frames = frames(&cipher(&session, &sample_data()).await);
+    frames.swap(0, 1);
+
+    let error = decipher(&session, &frames.concat()).await.unwrap_err();
+    assert_eq!(error.kind(), InvalidData);
+}
+
+#[tokio::test]
+async fn replayed_frame_is_rejected() {
+    let session = fdgse::Session::new(MIN_BLOCK_SIZE, fdgse::generate_key());
+    let mut frames = frames(&cipher(&session, &sample_data()).await);
+    frames.insert(1, frames[0].clone());
+
+    let error = decipher(&session, &frames.concat()).await.unwrap_err();
+    assert_eq!(error.kind(), InvalidData);
+}
+
+#[tokio::test]
+async fn flipped_flags_are_rejected() {
+    let session = fdgse::Session::new(MIN_BLOCK_SIZE, fdgse::generate_key());
+    let frames = frames(&cipher(&session, &sample_data()).await);
+
+    for (index, flag) in [
+        (0, FRAME_LAST),
+        (0, FRAME_REKEY),
+        (frames.len() - 1, FRAME_LAST),
+    ] {
+        let mut frames = frames.clone();
+        frames[index][0] ^= flag;
+        assert!(
+            decipher(&session, &frames.concat()).await.is_err(),
+            "flag {flag} flipped on frame {index}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn oversized_frame_is_rejected() {
+    let session = fdgse::Session::new(MIN_BLOCK_SIZE, fdgse::generate_key());
+    let mut stream = cipher(&session, &sample_data()).await;
+    stream[1..9].copy_from_slice(&u64::MAX.to_le_bytes());
+
+    let error = decipher(&session, &stream).await.unwrap_err();
+    assert_eq!(error.kind(), InvalidData);
+}
+
+#[tokio::test]
+async fn stream_round_trips_across_rekeys() {
+    let mut session = fdgse::Session::new(MIN_BLOCK_SIZE, fdgse::generate_key());
+    session.rekey_after = 1;
+    let data = sample_data();
+    let stream = cipher(&session, &data).await;
+
+    let rekeys = frames(&stream)
+        .iter()
+        .filter(|frame| frame[0] & FRAME_REKEY != 0)
+        .count();
+    assert!(rekeys >= 3);
+    assert_eq!(decipher(&session, &stream).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn frame_under_previous_key_is_rejected() {
+    let mut session = fdgse::Session::new(MIN_BLOCK_SIZE, fdgse::generate_key());
+    let data = sample_data();
+    // The same frame, at the same position of the stream, without the switch to the next key
+    let old_key_frames = frames(&cipher(&session, &data).await);
+    session.rekey_after = 1;
+    let mut frames = frames(&cipher(&session, &data).await);
+    assert_eq!(frames[1][0], FRAME_REKEY);
+    assert_eq!(frames[2].len(), old_key_frames[2].len());
+
+    frames[2].clone_from(&old_key_frames[2]);
+    let error = decipher(&session, &frames.concat()).await.unwrap_err();
+    assert_eq!(error.kind(), InvalidData);
+}
@@ -0,0 +1,148 @@
+use forgedbackup::fdgse::PROTOCOL_VERSION;
+use forgedbackup::fsas;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const CLIENT: &str = "client";
+const SERVER: &str = "server";
+
+// Offsets of the fields sent by the server, after its version and hostname
+const SERVER_HOSTNAME: usize = 4;
+const SERVER_EPHEMERAL_KEY: usize = SERVER_HOSTNAME + SERVER.len() + 512;
+const SERVER_SIGNATURE: usize = SERVER_EPHEMERAL_KEY + 32;
+// Offsets of the fields sent by the client
+const CLIENT_EPHEMERAL_KEY: usize = 512;
+const CLIENT_SIGNATURE: usize = CLIENT_EPHEMERAL_KEY + 32;
+
+// Both ends of a TCP connection
+async fn connection() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connect = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connect, listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+// Copies everything from `reader` to `writer`, flipping the byte at `tampered` if any
+async fn relay(
+    mut reader: tokio::net::tcp::OwnedReadHalf,
+    mut writer: tokio::net::tcp::OwnedWriteHalf,
+    tampered: Option<usize>,
+) {
+    let mut buffer = [0u8; 1024];
+    let mut position = 0;
+    while let Ok(len @ 1..) = reader.read(&mut buffer).await {
+        if let Some(offset) = tampered.filter(|offset| (position..position + len).contains(offset))
+        {
+            buffer[offset - position] ^= 1;
+        }
+        position += len;
+        if writer.write_all(&buffer[..len]).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Runs the handshake through a relay which may tamper with the bytes sent to the server or to the client.
+// Returns whether both peers succeeded and agreed on the same secret.
+async fn handshake(
+    client_keypair: &fsas::KeyPair,
+    server_keypair: &fsas::KeyPair,
+    client_hostname_on_server: &str,
+    to_server: Option<usize>,
+    to_client: Option<usize>,
+) -> bool {
+    let (mut client, relay_client) = connection().await;
+    let (relay_server, mut server) = connection().await;
+    let (from_client, to_client_half) = relay_client.into_split();
+    let (from_server, to_server_half) = relay_server.into_split();
+    tokio::spawn(relay(from_client, to_server_half, to_server));
+    tokio::spawn(relay(from_server, to_client_half, to_client));
+
+    let (client_secret, server_secret) = tokio::join!(
+        async move { fsas::authenticate_server(&mut client, CLIENT, SERVER, client_keypair).await },
+        async move {
+            fsas::authenticate_client(
+                &mut server,
+                client_hostname_on_server,
+                SERVER,
+                server_keypair,
+            )
+            .await
+        },
+    );
+
+    match (client_secret, server_secret) {
+        (Ok(client_secret), Ok(server_secret)) => {
+            client_secret.as_bytes() == server_secret.as_bytes()
+        }
+        _ => false,
+    }
+}
+
+#[tokio::test]
+async fn handshake_with_right_keys_succeeds() {
+    let keypair = fsas::generate_keypair();
+    assert!(handshake(&keypair, &keypair, CLIENT, None, None).await);
+}
+
+#[tokio::test]
+async fn handshake_with_wrong_keys_fails() {
+    let keypair = fsas::generate_keypair();
+    let other = fsas::generate_keypair();
+    assert!(!handshake(&keypair, &other, CLIENT, None, None).await);
+}
+
+#[tokio::test]
+async fn tampered_handshake_fails() {
+    let keypair = fsas::generate_keypair();
+
+    for offset in [CLIENT_EPHEMERAL_KEY, CLIENT_SIGNATURE] {
+        assert!(
+            !handshake(&keypair, &keypair, CLIENT, Some(offset), None).await,
+            "byte {offset} sent to the server tampered with"
+        );
+    }
+    for offset in [SERVER_HOSTNAME, SERVER_EPHEMERAL_KEY, SERVER_SIGNATURE] {
+        assert!(
+            !handshake(&keypair, &keypair, CLIENT, None, Some(offset)).await,
+            "byte {offset} sent to the client tampered with"
+        );
+    }
+}
+
+#[tokio::test]
+async fn handshake_for_another_client_fails() {
+    let keypair = fsas::generate_keypair();
+    assert!(!handshake(&keypair, &keypair, "other", None, None).await);
+}
+
+#[tokio::test]
+async fn reflected_signature_fails() {
+    let keypair = fsas::generate_keypair();
+    let (mut client, mut server) = connection().await;
+
+    // A fake server sends the signature of the client back as its own
+    let fake_server = tokio::spawn(async move {
+        server.write_u16_le(PROTOCOL_VERSION).await.unwrap();
+        server.write_u16_le(SERVER.len() as u16).await.unwrap();
+        server.write_all(SERVER.as_bytes()).await.unwrap();
+        let mut challenge_and_key = [1u8; 512 + 32];
+        // The base point, which is a valid ephemeral key
+        challenge_and_key[512..].fill(0);
+        challenge_and_key[512] = 9;
+        server.write_all(&challenge_and_key).await.unwrap();
+
+        let mut challenge_and_key = [0u8; 512 + 32];
+        server.read_exact(&mut challenge_and_key).await.unwrap();
+        let mut signature = [0u8; 64];
+        server.read_exact(&mut signature).await.unwrap();
+        server.write_all(&signature).await.unwrap();
+    });
+
+    let result = fsas::authenticate_server(&mut client, CLIENT, SERVER, &keypair).await;
+    fake_server.await.unwrap();
+    assert_eq!(
+        result.err().map(|e| e.to_string()).as_deref(),
+        Some("Failed to authenticate the peer")
+    );
+}
@@ -0,0 +1,272 @@
+#![cfg(unix)]
+
+use forgedbackup::{fadc, ftc};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+// Empty directory, unique to the test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("forgedbackup-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Archive holding a symlink to `target`, followed by a directory with the same path
+fn symlink_then_directory(target: &Path) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    header.set_mode(0o777);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    builder.append_link(&mut header, "a", target).unwrap();
+
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_size(0);
+    header.set_mode(0o777);
+    header.set_uid(1234);
+    header.set_gid(1234);
+    header.set_mtime(0);
+    builder
+        .append_data(&mut header, "a/", std::io::empty())
+        .unwrap();
+
+    builder.into_inner().unwrap()
+}
+
+// Stream holding the given entries, with "data" as the content of files
+async fn stream_of(entries: &[fadc::Entry]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for entry in entries {
+        entry.write_to(&mut stream).await.unwrap();
+        if entry.kind == fadc::EntryKind::File {
+            stream.extend_from_slice(b"data");
+            // Unchanged during the backup
+            stream.push(0);
+        }
+    }
+    stream
+}
+
+fn entry(kind: fadc::EntryKind, path: &Path, target: Option<&Path>) -> fadc::Entry {
+    fadc::Entry {
+        kind,
+        path: path.to_path_buf(),
+        metadata: fadc::EntryMetadata {
+            mode: 0o644,
+            ..fadc::EntryMetadata::default()
+        },
+        size: if kind == fadc::EntryKind::File { 4 } else { 0 },
+        target: target.map(Path::to_path_buf),
+        rdev: 0,
+    }
+}
+
+// Restores the stream, which must fail as it writes outside of the output directory
+async fn assert_refused(stream: &[u8], output: PathBuf, escaped: &Path) {
+    let error = fadc::write_dir(&mut &stream[..], output, &fadc::RestoreOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(!escaped.exists());
+}
+
+#[tokio::test]
+async fn parent_component_is_refused() {
+    let dir = test_dir("parent-component");
+    let stream = stream_of(&[entry(fadc::EntryKind::File, Path::new("../escaped"), None)]).await;
+
+    assert_refused(&stream, dir.join("output"), &dir.join("escaped")).await;
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn absolute_path_is_refused() {
+    let dir = test_dir("absolute-path");
+    let escaped = dir.join("escaped");
+    let stream = stream_of(&[entry(fadc::EntryKind::File, &escaped, None)]).await;
+
+    assert_refused(&stream, dir.join("output"), &escaped).await;
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn file_is_not_written_through_symlink() {
+    let dir = test_dir("symlink-file");
+    let victim = dir.join("victim");
+    std::fs::create_dir(&victim).unwrap();
+    let stream = stream_of(&[
+        entry(fadc::EntryKind::Symlink, Path::new("a"), Some(&victim)),
+        entry(fadc::EntryKind::File, Path::new("a/file"), None),
+    ])
+    .await;
+
+    assert_refused(&stream, dir.join("output"), &victim.join("file")).await;
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn directory_entry_does_not_follow_symlink() {
+    let dir = test_dir("symlink-directory");
+    let victim = dir.join("victim");
+    let output = dir.join("output");
+    std::fs::create_dir(&victim).unwrap();
+    std::fs::set_permissions(&victim, std::fs::Permissions::from_mode(0o700)).unwrap();
+    let owner = std::os::unix::fs::MetadataExt::uid(&std::fs::metadata(&victim).unwrap());
+
+    let mut stream = Vec::new();
+    ftc::import_stream(
+        std::io::Cursor::new(symlink_then_directory(&victim)),
+        &mut stream,
+    )
+    .await
+    .unwrap();
+    let _ = fadc::write_dir(
+        &mut stream.as_slice(),
+        output.clone(),
+        &fadc::RestoreOptions::default(),
+    )
+    .await;
+
+    let metadata = std::fs::metadata(&victim).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o700);
+    assert_eq!(std::os::unix::fs::MetadataExt::uid(&metadata), owner);
+    assert!(
+        !std::fs::symlink_metadata(output.join("a")).is_ok_and(|metadata| metadata.is_symlink())
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Archive holding a file with two hardlinks to it
+fn file_with_hardlinks() -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(4);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    builder
+        .append_data(&mut header, "file", b"data".as_slice())
+        .unwrap();
+
+    for link in ["a", "b"] {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        builder.append_link(&mut header, link, "file").unwrap();
+    }
+
+    builder.into_inner().unwrap()
+}
+
+// Restores the entries of the stream matching `paths`, returning how many were restored
+async fn restore_paths(stream: &[u8], output: &Path, paths: &[&str], find_links: bool) -> usize {
+    let options = fadc::RestoreOptions {
+        paths: fadc::PathFilter::new(paths).unwrap(),
+        ..fadc::RestoreOptions::default()
+    };
+    let mut links = fadc::DetachedLinks::default();
+    if find_links {
+        fadc::walk(&mut &stream[..], options.max_path_len, |entry| {
+            links.add(entry, &options);
+            Ok(())
+        })
+        .await
+        .unwrap();
+    }
+    fadc::write_selected(
+        &mut &stream[..],
+        output.to_path_buf(),
+        &options,
+        links,
+        None,
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn hardlink_is_restored_without_its_target() {
+    let dir = test_dir("hardlink-target");
+    let mut stream = Vec::new();
+    ftc::import_stream(std::io::Cursor::new(file_with_hardlinks()), &mut stream)
+        .await
+        .unwrap();
+
+    let output = dir.join("second");
+    assert_eq!(restore_paths(&stream, &output, &["b"], true).await, 1);
+    assert_eq!(std::fs::read(output.join("b")).unwrap(), b"data");
+    assert!(!output.join("file").exists());
+    assert!(!output.join("a").exists());
+
+    let output = dir.join("both");
+    assert_eq!(restore_paths(&stream, &output, &["a", "b"], true).await, 2);
+    let a = std::fs::metadata(output.join("a")).unwrap();
+    let b = std::fs::metadata(output.join("b")).unwrap();
+    assert_eq!(std::fs::read(output.join("b")).unwrap(), b"data");
+    assert_eq!(
+        std::os::unix::fs::MetadataExt::ino(&a),
+        std::os::unix::fs::MetadataExt::ino(&b)
+    );
+    assert!(!output.join("file").exists());
+
+    // Without knowing the links beforehand, the link is skipped and not counted
+    let output = dir.join("skipped");
+    assert_eq!(restore_paths(&stream, &output, &["b"], false).await, 0);
+    assert!(!output.join("b").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn hardlink_is_extracted_with_its_target_content() {
+    let dir = test_dir("hardlink-extract");
+    let mut stream = Vec::new();
+    ftc::import_stream(std::io::Cursor::new(file_with_hardlinks()), &mut stream)
+        .await
+        .unwrap();
+
+    let archive = dir.join("backup.fbk");
+    let storage = forgedbackup::config::StorageConfig::default();
+    let header =
+        forgedbackup::fce::ArchiveHeader::new("test", 0, storage.compression, storage.block_size);
+    forgedbackup::store_backup(&mut stream.as_slice(), &archive, &header, &storage)
+        .await
+        .unwrap();
+
+    let output = dir.join("output");
+    let mut index = tokio::fs::File::open(forgedbackup::fai::index_path(&archive))
+        .await
+        .unwrap();
+    let entry = forgedbackup::fai::extract(
+        &archive,
+        &mut index,
+        Path::new("b"),
+        output.clone(),
+        &fadc::RestoreOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(entry.unwrap().path, Path::new("b"));
+    assert_eq!(std::fs::read(output.join("b")).unwrap(), b"data");
+    assert!(!output.join("file").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}